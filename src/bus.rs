@@ -0,0 +1,114 @@
+// Anything other than plain RAM that sits on the address bus, EG. I/O registers.
+// A device claims addresses through handles and the CPU sends reads and writes
+// for those addresses to it instead of memory
+pub trait Bus {
+    // What to call the device in listings like CPU::memory_map_summary
+    fn name(&self) -> &str {
+        "device"
+    }
+    fn handles(&self, address: u16) -> bool;
+    // A read that leaves the device untouched, also used by debugging views
+    fn read(&self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    // Some registers change when they are read, EG. a status register that
+    // clears a flag once it has been seen. For those addresses the CPU calls
+    // read_mut on every access so the device can update itself
+    fn has_read_side_effects(&self, address: u16) -> bool {
+        let _ = address;
+        false
+    }
+    fn read_mut(&mut self, address: u16) -> u8 {
+        self.read(address)
+    }
+}
+
+// The bus a CPU gets by default, nothing sits over RAM so every address is
+// plain memory
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RamBus;
+
+impl Bus for RamBus {
+    fn name(&self) -> &str {
+        "ram"
+    }
+    fn handles(&self, _address: u16) -> bool {
+        false
+    }
+    fn read(&self, _address: u16) -> u8 {
+        0
+    }
+    fn write(&mut self, _address: u16, _value: u8) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    // A status register at $4000 whose ready bit clears once it's been read
+    struct Status {
+        ready: bool,
+    }
+
+    impl Bus for Status {
+        fn handles(&self, address: u16) -> bool {
+            address == 0x4000
+        }
+        fn read(&self, _address: u16) -> u8 {
+            (self.ready as u8) << 7
+        }
+        fn write(&mut self, _address: u16, _value: u8) {}
+        fn has_read_side_effects(&self, _address: u16) -> bool {
+            true
+        }
+        fn read_mut(&mut self, address: u16) -> u8 {
+            let value = self.read(address);
+            self.ready = false;
+            value
+        }
+    }
+
+    #[test]
+    fn status_bit_clears_after_one_read() {
+        let mut cpu = CPU::new();
+        cpu.add_device(Status { ready: true });
+        cpu.load_program(0x0200, &[0xAD, 0x00, 0x40, 0xAD, 0x00, 0x40]).unwrap();
+        cpu.set_pc(0x0200);
+        // Looking at it from a debugger doesn't count as a read
+        assert_eq!(cpu.peek(0x4000), 0x80);
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0x80);
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0x00);
+    }
+
+    // A board with a write-only output port at $4000
+    #[derive(Default)]
+    struct Port {
+        written: Vec<u8>,
+    }
+
+    impl Bus for Port {
+        fn handles(&self, address: u16) -> bool {
+            address == 0x4000
+        }
+        fn read(&self, _address: u16) -> u8 {
+            0
+        }
+        fn write(&mut self, _address: u16, value: u8) {
+            self.written.push(value);
+        }
+    }
+
+    #[test]
+    fn custom_bus_intercepts_writes() {
+        let mut cpu = CPU::with_bus(Port::default(), std::time::Duration::ZERO);
+        cpu.load_program(0x0200, &[0xA9, 0x42, 0x8D, 0x00, 0x40, 0x8D, 0x01, 0x40]).unwrap();
+        cpu.set_pc(0x0200);
+        cpu.run_bounded(3);
+        assert_eq!(cpu.bus().written, vec![0x42]);
+        // The next address along isn't the port's, so it's still RAM
+        assert_eq!(cpu.peek(0x4001), 0x42);
+        assert_eq!(cpu.peek(0x4000), 0);
+    }
+}
@@ -1,10 +1,29 @@
-use std::time::Duration;
-use std::{fmt::Display, sync::Arc};
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::ops::{ControlFlow, Range};
+use std::path::Path;
 use std::sync::Mutex;
 
-use crate::{instructions::{Instruction, init_instructions, Mode}};
+use crate::{bus::{Bus, RamBus}, devices::Framebuffer, trace::{BinaryTrace, Fetched, TraceEntry, TraceRecord}, disasm::{DisasmIter, format_instruction_with_symbols_for}, instructions::{Instruction, dispatch_table, init_cmos_instructions, init_instructions, Mode}, opcodes};
 
-#[derive(Clone, Copy)]
+// The one place two bytes become an address, the 6502 is little-endian so the
+// low byte always comes first in memory
+pub fn le_word(low: u8, high: u8) -> u16 {
+    low as u16 | ((high as u16) << 8)
+}
+
+// Pushes and pulls go to this page, offset by SP
+pub const STACK_PAGE: u16 = 0x0100;
+
+// Interrupt vectors, each holds a little-endian address
+pub const NMI_VECTOR: u16 = 0xFFFA;
+pub const RESET_VECTOR: u16 = 0xFFFC;
+pub const IRQ_VECTOR: u16 = 0xFFFE;
+// Pushing PC and P then fetching the vector, the same as BRK
+pub const INTERRUPT_CYCLES: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct StatRegister {
     pub negative: bool,
     pub overflow: bool,
@@ -45,14 +64,14 @@ impl Display for StatRegister {
 impl From<u8> for StatRegister {
     fn from(byte: u8) -> Self {
         Self {
-            negative: byte & 0x1 == 1,
-            overflow: byte & 0x2 == 1,
-            ignored: byte & 0x4 == 1,
-            sbreak: byte & 0x8 == 1,
-            decimal: byte & 0x10 == 1,
-            interrupt: byte & 0x20 == 1,
-            zero: byte & 0x40 == 1,
-            carry: byte & 0x80 == 1,
+            negative: byte & 0x80 != 0,
+            overflow: byte & 0x40 != 0,
+            ignored: byte & 0x20 != 0,
+            sbreak: byte & 0x10 != 0,
+            decimal: byte & 0x08 != 0,
+            interrupt: byte & 0x04 != 0,
+            zero: byte & 0x02 != 0,
+            carry: byte & 0x01 != 0,
         }
     }
 }
@@ -70,6 +89,209 @@ impl From<StatRegister> for u8 {
     }
 }
 
+// The status flags by name, for front ends that want to loop over them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flag {
+    Carry,
+    Zero,
+    Interrupt,
+    Decimal,
+    Break,
+    Overflow,
+    Negative,
+}
+
+impl Flag {
+    pub fn all() -> [Flag; 7] {
+        [
+            Flag::Carry,
+            Flag::Zero,
+            Flag::Interrupt,
+            Flag::Decimal,
+            Flag::Break,
+            Flag::Overflow,
+            Flag::Negative,
+        ]
+    }
+}
+
+impl StatRegister {
+    pub fn get(&self, flag: Flag) -> bool {
+        match flag {
+            Flag::Carry => self.carry,
+            Flag::Zero => self.zero,
+            Flag::Interrupt => self.interrupt,
+            Flag::Decimal => self.decimal,
+            Flag::Break => self.sbreak,
+            Flag::Overflow => self.overflow,
+            Flag::Negative => self.negative,
+        }
+    }
+
+    pub fn set(&mut self, flag: Flag, value: bool) {
+        let bit = match flag {
+            Flag::Carry => &mut self.carry,
+            Flag::Zero => &mut self.zero,
+            Flag::Interrupt => &mut self.interrupt,
+            Flag::Decimal => &mut self.decimal,
+            Flag::Break => &mut self.sbreak,
+            Flag::Overflow => &mut self.overflow,
+            Flag::Negative => &mut self.negative,
+        };
+        *bit = value;
+    }
+}
+
+// Things that happened while executing that a debugger may want to know about
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    // A write hit a protected range and was dropped, memory is left unchanged
+    ProtectedWrite { address: u16, value: u8 },
+    // PC wandered outside of the code range, usually ran off the end of a program
+    LeftCodeRange { pc: u16 },
+    // PC landed in a range marked as data
+    ExecutedData { pc: u16 },
+    // PC landed on RAM nothing has written, only reported the first time
+    ExecutedUninitialized { pc: u16 },
+    // Something inside a watched region was read or written
+    WatchRead { address: u16, value: u8 },
+    WatchWrite { address: u16, value: u8 },
+}
+
+// Which accesses a watch region reports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    fn matches(&self, write: bool) -> bool {
+        match self {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterruptKind {
+    Nmi,
+    Irq,
+}
+
+// What memory is filled with on power up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RamInit {
+    Zero,
+    // Every byte is a NOP, so running into unwritten memory just slides along
+    Nop,
+    Fill(u8),
+}
+
+impl RamInit {
+    pub fn byte(&self) -> u8 {
+        match self {
+            RamInit::Zero => 0x00,
+            RamInit::Nop => 0xEA,
+            RamInit::Fill(byte) => *byte,
+        }
+    }
+}
+
+// Why a bounded run gave control back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HaltReason {
+    // Ran the maximum number of instructions it was allowed to
+    InstructionLimit,
+    // The program wrote to a halt trap, exit_code has what it wrote
+    ResultTrap,
+    // The next instruction jumps or branches to itself so nothing more will happen
+    SelfLoop(u16),
+    // Used up the cycles try_run was given
+    CycleLimit,
+    // Ran a JAM opcode at this address, the chip is locked up until reset
+    Jammed(u16),
+}
+
+// Everything about how a bounded run went, the counts only cover that run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunResult {
+    pub reason: HaltReason,
+    pub cycles: u64,
+    pub instructions: u64,
+    pub final_pc: u16,
+}
+
+// Why try_run couldn't carry on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuError {
+    // Nothing implements the opcode and unknown_opcode says not to skip it
+    UnknownOpcode { opcode: u8, pc: u16 },
+    // Hit one of the JAM opcodes, the real chip locks up until reset
+    Jammed { pc: u16 },
+    // A segment runs past the top of memory
+    SegmentOverflow { start: u16, length: usize },
+    // Two segments would write some of the same addresses
+    SegmentOverlap { first: u16, second: u16 },
+    // The CPU is halted, nothing was run and PC is still here until a reset
+    Halted { pc: u16 },
+}
+
+impl Display for CpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CpuError::UnknownOpcode { opcode, pc } => write!(f, "Unknown opcode {:02X} at ${:04X}", opcode, pc),
+            CpuError::Jammed { pc } => write!(f, "Jammed at ${:04X}", pc),
+            CpuError::SegmentOverflow { start, length } =>
+                write!(f, "Segment of {} bytes at ${:04X} runs past the end of memory", length, start),
+            CpuError::SegmentOverlap { first, second } =>
+                write!(f, "Segments at ${:04X} and ${:04X} overlap", first, second),
+            CpuError::Halted { pc } => write!(f, "Halted at ${:04X}", pc),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+// What a write to a trap address does, the write still goes through as well
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrapAction {
+    // Stop with the byte written as the exit code
+    Halt,
+    // Treat the byte as a character being printed, collected for take_printed
+    PrintChar,
+    // Remember the byte as the exit code but keep going
+    SetExitCode,
+}
+
+// Which chip is being emulated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variant {
+    // The original, illegal opcodes and all
+    Nmos,
+    // The 65C02, which adds the bit instructions and drops the illegal opcodes
+    Cmos,
+}
+
+// What to do when the CPU hits an opcode it doesn't implement
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnknownOpcode {
+    Panic,
+    // Skip over it and its operands as if it was a NOP
+    TreatAsNop,
+}
+
+// Where execution goes after the current instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NextPc {
+    Fixed(u16),
+    // Conditional branches can end up in either place depending on the flags
+    Branch { taken: u16, not_taken: u16 },
+}
+
 pub struct Registers {
     pub pc: u16,
     pub ac: u8,
@@ -79,6 +301,12 @@ pub struct Registers {
     pub sp: u8,
 }
 
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Registers {
     pub fn new() -> Self {
         Self {
@@ -92,25 +320,104 @@ impl Registers {
     }
 
     pub fn increment_pc(&mut self) -> u16 {
-        self.pc += 1;
-        self.pc - 1
+        self.pc = self.pc.wrapping_add(1);
+        self.pc.wrapping_sub(1)
+    }
+    // Skips over several bytes at once, EG. an instruction's operands
+    pub fn increment_pc_by(&mut self, count: u16) -> u16 {
+        self.pc = self.pc.wrapping_add(count);
+        self.pc.wrapping_sub(count)
     }
     pub fn decrement_pc(&mut self) -> u16 {
-        self.pc -= 1;
-        self.pc + 1
+        self.pc = self.pc.wrapping_sub(1);
+        self.pc.wrapping_add(1)
     }
 }
 
-pub struct CPU {
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU<B: Bus = RamBus> {
     speed: std::time::Duration,
-    pub memory: Arc<Mutex<[i16; 0xFFFF]>>,
-    // Possibly change this so the stack uses space in memory
-    pub stack: [u8; 0xFF],
-    pub registers: Registers,
-    pub instructions: Arc<Vec<Box<dyn Instruction>>>,
+    pub memory: Arc<Mutex<[u8; 0x10000]>>,
+    pub ram_init: RamInit,
+    // Reset loads SP with this when set instead of going three below wherever
+    // it was, handy for tests that want the stack somewhere particular
+    pub initial_sp: Option<u8>,
+    // Everything else, instructions included, goes through the setters so N
+    // and Z stay in step with the value
+    registers: Registers,
+    // Private so they can't be swapped out from under the dispatch tables
+    instructions: Vec<Box<dyn Instruction<B>>>,
+    cmos_instructions: Vec<Box<dyn Instruction<B>>>,
+    // Index into the instruction sets above for each opcode, built once so
+    // dispatch doesn't have to search them every step
+    dispatch: [Option<usize>; 256],
+    cmos_dispatch: [Option<usize>; 256],
+    pub variant: Variant,
+    // Interrupt lines, latched until serviced at an instruction boundary
+    pub nmi_pending: bool,
+    pub irq_pending: bool,
+    // An NMI asserted partway through the next instruction, it becomes
+    // pending once the opcode has been fetched
+    nmi_after_fetch: bool,
+    // CLI, SEI and PLP change I after the interrupt lines have been polled,
+    // so for one instruction afterwards IRQs still see the old value
+    delayed_interrupt_flag: Option<bool>,
+    // Total instructions executed since the CPU was created
+    instructions_executed: u64,
+    // Cycles on top of the base cost picked up by the current instruction,
+    // EG. for crossing a page boundary
+    pub(crate) extra_cycles: u8,
+    // Total cycles executed since the CPU was created
+    cycles: u64,
+    binary_trace: Option<BinaryTrace>,
+    // One line of text per instruction
+    text_trace: Option<Box<dyn Write>>,
+    // Only trace instructions with a PC inside this range
+    trace_range: Option<Range<u16>>,
+    // Ranges of memory that are currently read-only
+    protected: Vec<Range<u16>>,
+    events: Vec<Event>,
+    watch_regions: Vec<(Range<u16>, WatchKind)>,
+    // The board's memory map, addresses it doesn't handle are plain RAM
+    bus: B,
+    // Memory mapped devices, checked in order before the bus
+    devices: Vec<Box<dyn Bus>>,
+    framebuffer: Option<Framebuffer>,
+    // Ranges that decode to a lower address and the mask that gets them there
+    mirrors: Vec<(Range<u16>, u16)>,
+    pub unknown_opcode: UnknownOpcode,
+    // Every unknown opcode run into as (pc, opcode), so a test ROM can be
+    // checked for them even when they're being skipped as NOPs
+    pub hit_unknown: Vec<(u16, u8)>,
+    // Run the undocumented NMOS opcodes, when off they count as unknown
+    pub illegal_opcodes: bool,
+    // Chips from before mid 1976 shipped without ROR, turning this off makes
+    // its opcodes unknown like they were on those
+    pub has_ror: bool,
+    // Stop running once the program is stuck jumping to itself, which is how
+    // most test programs signal they are done
+    pub stop_on_self_loop: bool,
+    // Set once the CPU has stopped for good, EG. by hitting a halt trap.
+    // Stepping does nothing from then on until a reset. Private so it can't
+    // be set without halt_reason, use halt() and is_halted()
+    halted: bool,
+    halt_reason: Option<HaltReason>,
+    // Magic addresses test ROMs write to to report back
+    traps: HashMap<u16, TrapAction>,
+    exit_code: Option<u8>,
+    // What PrintChar traps have been sent
+    printed: Vec<u8>,
+    // Where code is allowed to run from, checked before every instruction
+    code_range: Option<Range<u16>>,
+    data_ranges: Vec<Range<u16>>,
+    // Catches running off the end of a program into the RAM fill
+    pub warn_on_execute_uninitialized: bool,
+    warned_uninitialized: bool,
+    // Which addresses have been written through set_memory_at_address
+    written: Vec<bool>,
 }
 
-impl Display for CPU {
+impl<B: Bus> Display for CPU<B> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, 
             r"
@@ -122,78 +429,2024 @@ impl Display for CPU {
                     y: {:04x},
                     sp: {:04x},
                         {}
+                cycles: {}
             ",
             self.registers.pc,
             self.registers.ac,
             self.registers.x,
             self.registers.y,
             self.registers.sp,
-            self.registers.sr
+            self.registers.sr,
+            self.cycles
         )
     }
 }
 
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CPU {
     pub fn new() -> Self {
-        let mem: [i16; 0xFFFF] = [0xEA; 0xFFFF];
+        Self::with_bus(RamBus, std::time::Duration::from_millis(750))
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    // Builds a CPU around a custom memory map, EG. a board with I/O
+    // registers. Addresses the bus doesn't handle fall through to RAM
+    pub fn with_bus(bus: B, speed: std::time::Duration) -> Self {
+        let ram_init = RamInit::Nop;
+        let mem: [u8; 0x10000] = [ram_init.byte(); 0x10000];
+        let instructions = init_instructions();
+        let cmos_instructions = init_cmos_instructions();
         Self {
-            speed: std::time::Duration::from_millis(750),
+            speed,
             memory: Arc::new(Mutex::new(mem)),
-            stack: [0; 0xFF],
+            ram_init,
+            initial_sp: None,
             registers: Registers::new(),
-            instructions: Arc::new(init_instructions()),
+            dispatch: dispatch_table(&instructions),
+            cmos_dispatch: dispatch_table(&cmos_instructions),
+            instructions,
+            cmos_instructions,
+            variant: Variant::Nmos,
+            nmi_pending: false,
+            irq_pending: false,
+            nmi_after_fetch: false,
+            delayed_interrupt_flag: None,
+            instructions_executed: 0,
+            extra_cycles: 0,
+            cycles: 0,
+            binary_trace: None,
+            text_trace: None,
+            trace_range: None,
+            protected: Vec::new(),
+            events: Vec::new(),
+            watch_regions: Vec::new(),
+            bus,
+            devices: Vec::new(),
+            framebuffer: None,
+            mirrors: Vec::new(),
+            unknown_opcode: UnknownOpcode::Panic,
+            hit_unknown: Vec::new(),
+            illegal_opcodes: false,
+            has_ror: true,
+            stop_on_self_loop: false,
+            halted: false,
+            halt_reason: None,
+            traps: HashMap::new(),
+            exit_code: None,
+            printed: Vec::new(),
+            code_range: None,
+            data_ranges: Vec::new(),
+            warn_on_execute_uninitialized: false,
+            warned_uninitialized: false,
+            written: vec![false; 0x10000],
         }
     }
 
-    pub fn run(&mut self) {
-        let memory_lock = self.memory.clone();
+    // How long run waits between instructions, can be changed while running,
+    // EG. dropped to zero for a turbo button
+    pub fn speed(&self) -> std::time::Duration {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: std::time::Duration) {
+        self.speed = speed;
+    }
+
+    // Read only, writing a register has to go through its setter
+    ///
+    /// ```compile_fail,E0616
+    /// let mut cpu = grey6502::CPU::new();
+    /// cpu.registers.ac = 0x80;
+    /// ```
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn set_ac(&mut self, value: u8) {
+        self.registers.ac = value;
+        self.update_zero_negative(value);
+    }
+
+    pub fn set_x(&mut self, value: u8) {
+        self.registers.x = value;
+        self.update_zero_negative(value);
+    }
+
+    pub fn set_y(&mut self, value: u8) {
+        self.registers.y = value;
+        self.update_zero_negative(value);
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.registers.pc = pc;
+    }
+
+    pub fn set_sp(&mut self, sp: u8) {
+        self.registers.sp = sp;
+    }
+
+    pub fn set_status(&mut self, sr: StatRegister) {
+        self.registers.sr = sr;
+    }
+
+    // For the flags an instruction works out itself, EG. carry out of a shift
+    pub fn set_flag(&mut self, flag: Flag, value: bool) {
+        self.registers.sr.set(flag, value);
+    }
+
+    // The SO pin, pulling it sets V straight away without going through the ALU
+    pub fn set_overflow_pin(&mut self) {
+        self.registers.sr.overflow = true;
+    }
+
+    pub(crate) fn update_zero_negative(&mut self, value: u8) {
+        self.registers.sr.zero = value == 0;
+        self.registers.sr.negative = value & 0x80 != 0;
+    }
+
+    // Pressing the reset button, RAM and the registers other than PC are left
+    // alone. PC is loaded from the reset vector, interrupts are disabled and
+    // a halted CPU starts running again
+    pub fn soft_reset(&mut self) {
+        self.halted = false;
+        self.halt_reason = None;
+        self.nmi_pending = false;
+        self.irq_pending = false;
+        self.nmi_after_fetch = false;
+        self.delayed_interrupt_flag = None;
+        self.registers.sr.interrupt = true;
+        // Bit 5 isn't wired to anything and always reads back as set
+        self.registers.sr.ignored = true;
+        // Reset goes through the motions of an interrupt with the writes
+        // suppressed, so SP ends up three lower, EG. $FD after power on
+        self.registers.sp = match self.initial_sp {
+            Some(sp) => sp,
+            None => self.registers.sp.wrapping_sub(3),
+        };
+        self.registers.pc = self.read16(RESET_VECTOR);
+        // The reset sequence takes as long as any other interrupt
+        self.cycles += INTERRUPT_CYCLES as u64;
+    }
+
+    // Power on without touching RAM, so a program and its vector at $FFFC
+    // can be loaded first. The registers start from zero which leaves SP at
+    // $FD once the reset sequence is done
+    pub fn reset(&mut self) {
+        self.registers = Registers::new();
+        self.soft_reset();
+    }
+
+    // Power cycling, RAM is refilled according to ram_init and everything else
+    // starts over before going through the reset sequence
+    pub fn hard_reset(&mut self) {
+        let fill = self.ram_init.byte();
+        self.memory.lock().expect("Failed to lock memory").fill(fill);
+        self.registers = Registers::new();
+        self.events.clear();
+        self.hit_unknown.clear();
+        self.written.fill(false);
+        self.warned_uninitialized = false;
+        self.exit_code = None;
+        self.printed.clear();
+        self.reset_counters();
+        self.soft_reset();
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions_executed
+    }
+
+    // Zeroes the cycle and instruction counts so just part of a program can
+    // be measured, nothing else is touched
+    pub fn reset_counters(&mut self) {
+        self.cycles = 0;
+        self.instructions_executed = 0;
+    }
+
+    // Runs from wherever PC is, call reset() first to start at the reset vector
+    pub fn run(&mut self) -> HaltReason {
         let mut time = std::time::Instant::now();
         loop {
-            let memory = memory_lock.lock().unwrap();
+            if let Some(reason) = self.stop_reason() {
+                let _ = self.flush_trace();
+                return reason;
+            }
             if time.elapsed() >= self.speed {
-                println!("{}", self);
-                let instruct = memory[self.registers.pc as usize];
-                drop(memory);
-                self.execute_instruction(&instruct);
+                // Dumping the registers to the terminal is for when there's
+                // no trace going somewhere else
+                if self.tracing() && self.text_trace.is_none() && self.binary_trace.is_none() {
+                    println!("{}", self);
+                }
+                self.step();
                 time = std::time::Instant::now();
             }
         }
     }
 
-    pub fn push_to_stack(&mut self, value: u8) {
-        if self.registers.sp as usize == self.stack.len() {
-            self.registers.sp = 0;
+    // Runs flat out without the speed delay until max_instructions have been
+    // executed, so a program that never finishes can't hang the caller
+    pub fn run_bounded(&mut self, max_instructions: u64) -> RunResult {
+        let (cycles, instructions) = (self.cycles, self.instructions_executed);
+        let mut reason = HaltReason::InstructionLimit;
+        for _ in 0..max_instructions {
+            if let Some(stopped) = self.stop_reason() {
+                reason = stopped;
+                break;
+            }
+            self.step();
         }
-        self.stack[self.registers.sp as usize] = value;
-        self.registers.sp = self.registers.sp.wrapping_add(1);
+        let _ = self.flush_trace();
+        let reason = self.stop_reason().unwrap_or(reason);
+        self.run_result(reason, cycles, instructions)
     }
 
-    pub fn pull_from_stack(&mut self) -> u8 {
-        if self.registers.sp as usize == 0 {
-            self.registers.sp = self.stack.len() as u8;
+    fn run_result(&self, reason: HaltReason, cycles: u64, instructions: u64) -> RunResult {
+        RunResult {
+            reason,
+            cycles: self.cycles - cycles,
+            instructions: self.instructions_executed - instructions,
+            final_pc: self.registers.pc,
+        }
+    }
+
+    // Steps n instructions and hands back what each one did, for comparing
+    // a run against a golden trace
+    pub fn run_steps(&mut self, n: usize) -> Vec<TraceEntry> {
+        (0..n).map(|_| {
+            // Same as step, but the instruction is only looked at once any
+            // interrupt has moved PC to its handler
+            let interrupt_cycles = if self.halted { 0 } else { self.poll_interrupts() };
+            let pc = self.registers.pc;
+            let opcode = self.inspect_memory_at_address(pc);
+            let cycles = if self.halted { 0 } else { interrupt_cycles + self.step_instruction() };
+            TraceEntry {
+                pc,
+                opcode,
+                a: self.registers.ac,
+                x: self.registers.x,
+                y: self.registers.y,
+                // Bit 5 reads as set like it does when pushed
+                p: u8::from(self.registers.sr) | 0x20,
+                sp: self.registers.sp,
+                cycles,
+            }
+        }).collect()
+    }
+
+    // Runs flat out until the program halts, loops on itself or max_cycles
+    // have gone by. Unlike run an opcode that would panic comes back as an
+    // error instead, with PC left on it
+    pub fn try_run(&mut self, max_cycles: u64) -> Result<RunResult, CpuError> {
+        let (start, instructions) = (self.cycles, self.instructions_executed);
+        let result = loop {
+            if let Some(reason) = self.stop_reason() {
+                break Ok(reason);
+            }
+            if self.is_at_infinite_loop() {
+                break Ok(HaltReason::SelfLoop(self.registers.pc));
+            }
+            if self.cycles - start >= max_cycles {
+                break Ok(HaltReason::CycleLimit);
+            }
+            if let Err(error) = self.try_step() {
+                break Err(error);
+            }
+        };
+        let _ = self.flush_trace();
+        result.map(|reason| self.run_result(reason, start, instructions))
+    }
+
+    // Runs flat out, handing control to the callback after every instruction
+    // so the caller can draw a frame or whatever else. Stops when the callback
+    // says so or the CPU halts
+    pub fn run_with_callback(&mut self, mut callback: impl FnMut(&mut Self) -> ControlFlow<()>) {
+        while !self.halted {
+            self.step();
+            if callback(self).is_break() {
+                break;
+            }
+        }
+    }
+
+    pub fn halt(&mut self, reason: HaltReason) {
+        self.halted = true;
+        self.halt_reason = Some(reason);
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    fn stop_reason(&self) -> Option<HaltReason> {
+        if self.halted {
+            return Some(self.halt_reason.unwrap_or(HaltReason::ResultTrap));
+        }
+        if self.stop_on_self_loop && self.is_at_infinite_loop() {
+            return Some(HaltReason::SelfLoop(self.registers.pc));
         }
+        None
+    }
+
+    // Whether the next instruction is a JMP to itself, or a branch to itself
+    // that the current flags will take
+    pub fn is_at_infinite_loop(&self) -> bool {
+        let pc = self.registers.pc;
+        let opcode = self.inspect_memory_at_address(pc);
+        match self.next_pc() {
+            NextPc::Fixed(next) => next == pc && (opcode == 0x4C || opcode == 0x6C),
+            NextPc::Branch { taken, .. } => taken == pc && self.branch_taken(opcode),
+        }
+    }
+
+    // Whether a branch opcode would branch with the current flags. For the
+    // 65C02's BBR/BBS that's the bit they test, read from the instruction at PC
+    pub fn branch_taken(&self, opcode: u8) -> bool {
+        let sr = self.registers.sr;
+        if self.variant == Variant::Cmos && opcode & 0x0F == 0x0F {
+            let address = self.inspect_memory_at_address(self.registers.pc.wrapping_add(1));
+            let bit = (opcode >> 4) & 0x07;
+            let set = self.inspect_memory_at_address(address as u16) & (1 << bit) != 0;
+            return set == (opcode & 0x80 != 0);
+        }
+        match opcode {
+            0x10 => !sr.negative,
+            0x30 => sr.negative,
+            0x50 => !sr.overflow,
+            0x70 => sr.overflow,
+            0x90 => !sr.carry,
+            0xB0 => sr.carry,
+            0xD0 => !sr.zero,
+            0xF0 => sr.zero,
+            _ => false,
+        }
+    }
+
+    // Puts an instruction at PC and steps over it, so a single instruction can
+    // be tried out without assembling a program first. Returns the cycles
+    pub fn execute_opcode(&mut self, opcode: u8, operands: &[u8]) -> u8 {
+        let pc = self.registers.pc;
+        self.set_memory_at_address(pc, opcode);
+        for (offset, operand) in operands.iter().enumerate() {
+            self.set_memory_at_address(pc.wrapping_add(1 + offset as u16), *operand);
+        }
+        self.step()
+    }
+
+    // Services any pending interrupt then executes the instruction at PC,
+    // returns how many cycles the instruction took including any penalties
+    pub fn step(&mut self) -> u8 {
+        // Nothing runs on a halted CPU, it takes no cycles and PC stays put
+        if self.halted {
+            return 0;
+        }
+        let interrupt_cycles = self.poll_interrupts();
+        interrupt_cycles + self.step_instruction()
+    }
+
+    // The part of step after interrupts, runs whatever is at PC now
+    fn step_instruction(&mut self) -> u8 {
+        self.check_pc();
+        let instruct = self.get_memory_at_address(self.registers.pc);
+        if self.nmi_after_fetch {
+            self.nmi_after_fetch = false;
+            self.nmi_pending = true;
+        }
+        let record = TraceRecord {
+            pc: self.registers.pc,
+            opcode: instruct,
+            a: self.registers.ac,
+            x: self.registers.x,
+            y: self.registers.y,
+            // Bit 5 isn't a real flag and always reads back as set
+            p: u8::from(self.registers.sr) | 0x20,
+            sp: self.registers.sp,
+            cycle: self.cycles as u32,
+        };
+        // Written before the instruction runs, with the operand bytes as they
+        // are now, so an instruction that changes them still shows what it read
+        if self.tracing() {
+            self.write_trace(&record);
+        }
+        self.extra_cycles = 0;
+        let interrupt_flag = self.registers.sr.interrupt;
+        self.delayed_interrupt_flag = None;
+        self.execute_instruction(&instruct);
+        // RTI isn't in here, the I it pulls counts straight away
+        if let 0x58 | 0x78 | 0x28 = instruct {
+            self.delayed_interrupt_flag = Some(interrupt_flag);
+        }
+        self.instructions_executed += 1;
+        let base = match self.variant {
+            Variant::Cmos => opcodes::cmos_cycles(instruct),
+            Variant::Nmos => None,
+        };
+        let cycles = base.unwrap_or(opcodes::CYCLES[instruct as usize]) + self.extra_cycles;
+        self.cycles += cycles as u64;
+        cycles
+    }
+
+    // Same as step but an opcode that would panic is handed back as an error
+    // instead. When an interrupt is due it's the handler's opcode that gets
+    // checked, before the interrupt is taken, so an error leaves the CPU as it
+    // was with the interrupt still pending and no cycles charged. A halted CPU
+    // still does nothing, it just says so with Halted rather than looking like
+    // a free instruction
+    pub fn try_step(&mut self) -> Result<u8, CpuError> {
+        if self.halted {
+            return Err(CpuError::Halted { pc: self.registers.pc });
+        }
+        let pc = match self.would_service_interrupt() {
+            Some(InterruptKind::Nmi) => self.read16(NMI_VECTOR),
+            Some(InterruptKind::Irq) => self.read16(IRQ_VECTOR),
+            None => self.registers.pc,
+        };
+        let opcode = self.inspect_memory_at_address(pc);
+        if !self.is_implemented(opcode) && self.unknown_opcode == UnknownOpcode::Panic {
+            if opcodes::mnemonic(opcode) == "JAM" && self.variant == Variant::Nmos {
+                return Err(CpuError::Jammed { pc });
+            }
+            return Err(CpuError::UnknownOpcode { opcode, pc });
+        }
+        Ok(self.step())
+    }
+
+    fn write_trace(&mut self, record: &TraceRecord) {
+        // Nowhere to report a failed write from in here, so the trace is
+        // dropped rather than failing on every step after
+        if let Some(trace) = self.binary_trace.as_mut() {
+            if trace.record(record).is_err() {
+                self.binary_trace = None;
+            }
+        }
+        if self.text_trace.is_none() {
+            return;
+        }
+        // Peeked rather than read so devices don't see the trace looking
+        let (_, mode) = opcodes::decode_for(record.opcode, self.variant);
+        let mut fetched = Fetched::default();
+        fetched.push(record.opcode);
+        for offset in 1..=mode.operand_length() {
+            fetched.push(self.inspect_memory_at_address(record.pc.wrapping_add(offset)));
+        }
+        if let Some(trace) = self.text_trace.as_mut() {
+            if writeln!(trace, "{}", record.with_bytes_for(self.variant, fetched.as_slice())).is_err() {
+                self.text_trace = None;
+            }
+        }
+    }
+
+    pub fn set_text_trace(&mut self, writer: Option<Box<dyn Write>>) {
+        self.text_trace = writer;
+    }
+
+    // Writes a nestest style line for every instruction, EG. for diffing
+    // against a known good log. Off until this is called
+    pub fn set_trace(&mut self, writer: impl Write + 'static) {
+        self.text_trace = Some(Box::new(writer));
+    }
+
+    // Streams the text trace into a file instead of the terminal, buffered
+    // since there's a line for every instruction
+    pub fn trace_to_file(&mut self, path: &Path) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.text_trace = Some(Box::new(BufWriter::new(file)));
+        Ok(())
+    }
+
+    // Pushes out anything still sitting in the trace buffers
+    pub fn flush_trace(&mut self) -> io::Result<()> {
+        if let Some(trace) = self.text_trace.as_mut() {
+            trace.flush()?;
+        }
+        if let Some(trace) = self.binary_trace.as_mut() {
+            trace.flush()?;
+        }
+        Ok(())
+    }
+
+    // Limits tracing to instructions inside the range, EG. one subroutine
+    pub fn set_trace_range(&mut self, range: Option<Range<u16>>) {
+        self.trace_range = range;
+    }
+
+    fn tracing(&self) -> bool {
+        match &self.trace_range {
+            Some(range) => range.contains(&self.registers.pc),
+            None => true,
+        }
+    }
+
+    // Records every instruction executed by step to the writer as 12 byte
+    // records, see trace::binary_trace_to_text for reading it back
+    pub fn set_binary_trace(&mut self, writer: Option<Box<dyn Write>>) {
+        self.binary_trace = writer.map(BinaryTrace::new);
+    }
+
+    // Test ROMs often report how they did by writing a status byte somewhere,
+    // a write to this address stops the CPU with that byte as the exit code
+    pub fn set_result_trap(&mut self, address: u16) {
+        self.set_trap(address, TrapAction::Halt);
+    }
+
+    // Different test ROMs have different conventions, EG. one address to
+    // print characters to and another for the final result, so any number
+    // of addresses can be trapped at once
+    pub fn set_trap(&mut self, address: u16, action: TrapAction) {
+        self.traps.insert(address, action);
+    }
+
+    pub fn remove_trap(&mut self, address: u16) {
+        self.traps.remove(&address);
+    }
+
+    // Hands over what's been printed through PrintChar traps since the last call
+    pub fn take_printed(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.printed)
+    }
+
+    pub fn exit_code(&self) -> Option<u8> {
+        self.exit_code
+    }
+
+    // Everything outside of this range is treated as not being code
+    pub fn set_code_range(&mut self, range: Option<Range<u16>>) {
+        self.code_range = range;
+    }
+
+    pub fn mark_data_range(&mut self, range: Range<u16>) {
+        self.data_ranges.push(range);
+    }
+
+    fn check_pc(&mut self) {
+        let pc = self.registers.pc;
+        if let Some(range) = &self.code_range {
+            if !range.contains(&pc) {
+                self.events.push(Event::LeftCodeRange { pc });
+            }
+        }
+        if self.data_ranges.iter().any(|r| r.contains(&pc)) {
+            self.events.push(Event::ExecutedData { pc });
+        }
+        if self.warn_on_execute_uninitialized && !self.warned_uninitialized && !self.is_initialized(pc) {
+            self.warned_uninitialized = true;
+            self.events.push(Event::ExecutedUninitialized { pc });
+        }
+    }
+
+    // Programs are usually loaded straight into memory rather than through
+    // set_memory_at_address, so anything that differs from the RAM fill
+    // counts as written too
+    fn is_initialized(&self, address: u16) -> bool {
+        self.written[address as usize] || self.inspect_memory_at_address(address) != self.ram_init.byte()
+    }
+
+    // Resolves an addressing mode with the operand bytes after PC, exactly like
+    // an instruction would, so each mode can be checked on its own. PC is left
+    // on the last operand byte and the bool is whether a page was crossed
+    pub fn compute_effective_address(&mut self, mode: Mode) -> (u16, bool) {
+        mode.get_address(self)
+    }
+
+    // Moves PC onto the next operand byte and reads it
+    pub fn fetch_operand(&mut self) -> u8 {
+        self.registers.pc = self.registers.pc.wrapping_add(1);
+        self.get_memory_at_address(self.registers.pc)
+    }
+
+    // Two operand bytes, low byte first
+    pub fn fetch_operand_word(&mut self) -> u16 {
+        let low = self.fetch_operand();
+        let high = self.fetch_operand();
+        le_word(low, high)
+    }
+
+    // The stack is page one of memory growing downwards, SP points at the next
+    // free byte so a push writes then decrements and a pull increments then reads
+    pub fn push_to_stack(&mut self, value: u8) {
+        let address = STACK_PAGE | self.registers.sp as u16;
+        self.set_memory_at_address(address, value);
         self.registers.sp = self.registers.sp.wrapping_sub(1);
-        self.stack[self.registers.sp.wrapping_add(1) as usize]
     }
 
-    pub fn get_memory_at_address(&self, address: u16) -> i16 {
-        let memory_lock = self.memory.clone();
-        let memory = memory_lock.lock().expect("Failed to lock memory");
-        let out = memory[address as usize];
-        drop(memory);
-        out
+    pub fn pull_from_stack(&mut self) -> u8 {
+        self.registers.sp = self.registers.sp.wrapping_add(1);
+        let address = STACK_PAGE | self.registers.sp as u16;
+        self.get_memory_at_address(address)
+    }
+
+    // What the nth pull_from_stack from now would return, without pulling
+    pub fn peek_stack(&self, depth: u8) -> u8 {
+        let sp = self.registers.sp.wrapping_add(1).wrapping_add(depth);
+        self.inspect_memory_at_address(STACK_PAGE | sp as u16)
     }
 
-    pub fn execute_instruction(&mut self, opcode: &i16) {
-        let instructions = self.instructions.clone();
-        let instruction = match instructions.iter().find(|i| i.get_opcodes().contains(opcode)) {
-            Some(i) => i,
-            None => {
-                panic!("An unknown instruction was called");
+    // Best guess at the return addresses on the stack, innermost first. Only
+    // pairs of bytes that point just after a JSR opcode count, so data pushed
+    // with PHA is skipped over rather than showing up as a bogus frame
+    pub fn call_stack(&self) -> Vec<u16> {
+        let mut frames = Vec::new();
+        let mut sp = self.registers.sp as u16 + 1;
+        while sp < 0xFF {
+            let low = self.inspect_memory_at_address(STACK_PAGE | sp);
+            let high = self.inspect_memory_at_address(STACK_PAGE | (sp + 1));
+            let pushed = le_word(low, high);
+            if self.inspect_memory_at_address(pushed.wrapping_sub(2)) == 0x20 {
+                frames.push(pushed.wrapping_add(1));
+                sp += 2;
+            } else {
+                sp += 1;
+            }
+        }
+        frames
+    }
+
+    // Little-endian word at address and address + 1, without disturbing devices
+    pub fn read16(&self, address: u16) -> u16 {
+        let low = self.inspect_memory_at_address(address);
+        let high = self.inspect_memory_at_address(address.wrapping_add(1));
+        le_word(low, high)
+    }
+
+    pub fn write16(&mut self, address: u16, value: u16) {
+        self.set_memory_at_address(address, value as u8);
+        self.set_memory_at_address(address.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    pub fn set_nmi_vector(&mut self, address: u16) {
+        self.write16(NMI_VECTOR, address);
+    }
+
+    pub fn set_reset_vector(&mut self, address: u16) {
+        self.write16(RESET_VECTOR, address);
+    }
+
+    pub fn set_irq_vector(&mut self, address: u16) {
+        self.write16(IRQ_VECTOR, address);
+    }
+
+    pub fn disassemble(&self, start: u16) -> DisasmIter<'_, B> {
+        DisasmIter::new(self, start)
+    }
+
+    // A listing of start..end where every address with a symbol gets a label
+    // line above it and is referred to by name in operands, EG.
+    //  init:
+    //  C010  A9 00     LDA #$00
+    //  C012  20 10 C0  JSR init
+    pub fn annotated_listing(&self, start: u16, end: u16, symbols: &HashMap<u16, String>) -> String {
+        let mut listing = String::new();
+        for (address, bytes, _) in self.disassemble(start).take_while(|(address, _, _)| *address < end) {
+            if let Some(label) = symbols.get(&address) {
+                listing.push_str(&format!("{}:\n", label));
+            }
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let text = format_instruction_with_symbols_for(self.variant, address, bytes[0], &bytes[1..], symbols);
+            listing.push_str(&format!("{:04X}  {:<8}  {}\n", address, hex.join(" "), text));
+        }
+        listing
+    }
+
+    // Addressing mode of the instruction that's about to execute
+    pub fn current_mode(&self) -> Mode {
+        let opcode = self.inspect_memory_at_address(self.registers.pc);
+        opcodes::decode_for(opcode, self.variant).1
+    }
+
+    // Decodes the instruction at PC and works out where PC ends up after it,
+    // without executing anything
+    pub fn next_pc(&self) -> NextPc {
+        let pc = self.registers.pc;
+        let opcode = self.inspect_memory_at_address(pc);
+        let (_, mode) = opcodes::decode_for(opcode, self.variant);
+        let after = pc.wrapping_add(1 + mode.operand_length());
+        match opcode {
+            // BRK
+            0x00 => NextPc::Fixed(self.read16(IRQ_VECTOR)),
+            // JSR, JMP absolute
+            0x20 | 0x4C => NextPc::Fixed(self.read16(pc.wrapping_add(1))),
+            // JMP indirect, the high byte of the pointer doesn't carry into the next page
+            0x6C => {
+                let pointer = self.read16(pc.wrapping_add(1));
+                let low = self.inspect_memory_at_address(pointer);
+                let high_address = (pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF);
+                let high = self.inspect_memory_at_address(high_address);
+                NextPc::Fixed(le_word(low, high))
+            },
+            // RTI, skip the status byte
+            0x40 => NextPc::Fixed(le_word(self.peek_stack(1), self.peek_stack(2))),
+            // RTS, the pushed address is one short of where it returns to
+            0x60 => NextPc::Fixed(le_word(self.peek_stack(0), self.peek_stack(1)).wrapping_add(1)),
+            _ if mode == Mode::Relative => {
+                let offset = self.inspect_memory_at_address(pc.wrapping_add(1)) as i8;
+                NextPc::Branch {
+                    taken: after.wrapping_add(offset as u16),
+                    not_taken: after,
+                }
+            },
+            // 65C02 BBR/BBS, the offset comes after the zero page address
+            _ if mode == Mode::ZeropageRelative => {
+                let offset = self.inspect_memory_at_address(pc.wrapping_add(2)) as i8;
+                NextPc::Branch {
+                    taken: after.wrapping_add(offset as u16),
+                    not_taken: after,
+                }
+            },
+            _ => NextPc::Fixed(after),
+        }
+    }
+
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn add_device<D: Bus + 'static>(&mut self, device: D) {
+        self.devices.push(Box::new(device));
+    }
+
+    // The read path used while executing, devices get told about the read
+    pub fn get_memory_at_address(&mut self, address: u16) -> u8 {
+        let address = self.resolve_mirror(address);
+        let value = match self.devices.iter_mut().find(|d| d.handles(address)) {
+            Some(device) if device.has_read_side_effects(address) => device.read_mut(address),
+            Some(device) => device.read(address),
+            None if self.bus.handles(address) => match self.bus.has_read_side_effects(address) {
+                true => self.bus.read_mut(address),
+                false => self.bus.read(address),
+            },
+            None => self.inspect_memory_at_address(address),
+        };
+        if self.watched(address, false) {
+            self.events.push(Event::WatchRead { address, value });
+        }
+        value
+    }
+
+    pub fn set_framebuffer(&mut self, framebuffer: Option<Framebuffer>) {
+        self.framebuffer = framebuffer;
+    }
+
+    // The screen as RGBA pixels, empty when there's no framebuffer
+    pub fn framebuffer_rgba(&self) -> Vec<u8> {
+        match &self.framebuffer {
+            Some(framebuffer) => framebuffer.rgba(self),
+            None => Vec::new(),
+        }
+    }
+
+    // One line per configured region, EG. "$0800-$1FFF  mirror of $0000-$07FF",
+    // RAM first since everything else sits on top of it
+    pub fn memory_map_summary(&self) -> String {
+        let mut lines = vec![String::from("$0000-$FFFF  RAM")];
+        let span = |range: &Range<u16>| format!("${:04X}-${:04X}", range.start, range.end.wrapping_sub(1));
+        for (range, mask) in &self.mirrors {
+            let copy = range.start & mask..(range.start & mask).wrapping_add(mask.wrapping_add(1));
+            lines.push(format!("{}  mirror of {}", span(range), span(&copy)));
+        }
+        for range in &self.protected {
+            lines.push(format!("{}  ROM", span(range)));
+        }
+        // Devices only answer whether they handle an address, so their
+        // regions are found by walking the whole address space
+        let bus = std::iter::once(&self.bus as &dyn Bus);
+        for device in bus.chain(self.devices.iter().map(|d| d.as_ref())) {
+            let mut start = None;
+            for address in 0..=0x10000u32 {
+                let handled = address <= 0xFFFF && device.handles(address as u16);
+                match (handled, start) {
+                    (true, None) => start = Some(address),
+                    (false, Some(first)) => {
+                        lines.push(format!("${:04X}-${:04X}  {}", first, address - 1, device.name()));
+                        start = None;
+                    },
+                    _ => {},
+                }
+            }
+        }
+        // A framebuffer with no pixels doesn't take up any addresses
+        if let Some(framebuffer) = self.framebuffer.as_ref().filter(|f| f.width * f.height > 0) {
+            let end = framebuffer.start as usize + framebuffer.width * framebuffer.height - 1;
+            lines.push(format!("${:04X}-${:04X}  framebuffer", framebuffer.start, end));
+        }
+        let mut traps: Vec<_> = self.traps.iter().collect();
+        traps.sort_by_key(|(address, _)| **address);
+        for (address, action) in traps {
+            lines.push(format!("${:04X}        trap ({:?})", address, action));
+        }
+        lines.join("\n")
+    }
+
+    // Addresses in the range only decode the bits in mask, so they land on a
+    // copy lower down, EG. add_mirror(0x0800..0x2000, 0x07FF) repeats the
+    // first 2K of RAM up to $1FFF like the NES does
+    pub fn add_mirror(&mut self, range: Range<u16>, mask: u16) {
+        self.mirrors.push((range, mask));
+    }
+
+    fn resolve_mirror(&self, address: u16) -> u16 {
+        match self.mirrors.iter().find(|(r, _)| r.contains(&address)) {
+            Some((_, mask)) => address & mask,
+            None => address,
+        }
+    }
+
+    // The byte a load from the address would see, going through mirrors and
+    // devices, but without the side effects of a real read. Use this for
+    // memory viewers rather than looking at the memory array
+    pub fn effective_read(&self, address: u16) -> u8 {
+        self.inspect_memory_at_address(address)
+    }
+
+    // Reads without disturbing any devices, for looking at memory from outside
+    pub fn inspect_memory_at_address(&self, address: u16) -> u8 {
+        let address = self.resolve_mirror(address);
+        if let Some(device) = self.devices.iter().find(|d| d.handles(address)) {
+            return device.read(address);
+        }
+        if self.bus.handles(address) {
+            return self.bus.read(address);
+        }
+        // Lock in place, cloning the Arc is only needed to share memory with another thread
+        self.memory.lock().expect("Failed to lock memory")[address as usize]
+    }
+
+    pub fn set_memory_at_address(&mut self, address: u16, value: u8) {
+        let address = self.resolve_mirror(address);
+        if self.protected.iter().any(|r| r.contains(&address)) {
+            self.events.push(Event::ProtectedWrite { address, value });
+            return;
+        }
+        if self.watched(address, true) {
+            self.events.push(Event::WatchWrite { address, value });
+        }
+        match self.traps.get(&address) {
+            Some(TrapAction::Halt) => {
+                self.exit_code = Some(value);
+                self.halt(HaltReason::ResultTrap);
+            },
+            Some(TrapAction::PrintChar) => self.printed.push(value),
+            Some(TrapAction::SetExitCode) => self.exit_code = Some(value),
+            None => {},
+        }
+        if let Some(device) = self.devices.iter_mut().find(|d| d.handles(address)) {
+            device.write(address, value);
+            return;
+        }
+        if self.bus.handles(address) {
+            self.bus.write(address, value);
+            return;
+        }
+        self.written[address as usize] = true;
+        self.memory.lock().expect("Failed to lock memory")[address as usize] = value;
+    }
+
+    // Copies everything the reader has into memory from start, EG. an
+    // assembled binary piped in on stdin. Returns how many bytes were loaded
+    pub fn load_from_reader<R: Read>(&mut self, reader: &mut R, start: u16) -> io::Result<usize> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        if start as usize + bytes.len() > 0x10000 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{} bytes don't fit in memory from ${:04X}", bytes.len(), start)));
+        }
+        for (offset, byte) in bytes.iter().enumerate() {
+            self.set_memory_at_address(start + offset as u16, *byte);
+        }
+        Ok(bytes.len())
+    }
+
+    // Loads code and data that live apart, EG. the sections a linker puts
+    // out. Everything is checked before anything is written, so a bad
+    // segment leaves memory as it was
+    pub fn load_segments(&mut self, segments: &[(u16, &[u8])]) -> Result<(), CpuError> {
+        for (i, (start, bytes)) in segments.iter().enumerate() {
+            let end = *start as usize + bytes.len();
+            if end > 0x10000 {
+                return Err(CpuError::SegmentOverflow { start: *start, length: bytes.len() });
+            }
+            for (other, other_bytes) in &segments[..i] {
+                let other_end = *other as usize + other_bytes.len();
+                if (*start as usize) < other_end && (*other as usize) < end {
+                    return Err(CpuError::SegmentOverlap { first: *other, second: *start });
+                }
+            }
+        }
+        for (start, bytes) in segments {
+            for (offset, byte) in bytes.iter().enumerate() {
+                self.set_memory_at_address(start + offset as u16, *byte);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load_program(&mut self, start: u16, bytes: &[u8]) -> Result<(), CpuError> {
+        self.load_segments(&[(start, bytes)])
+    }
+
+    // Loads the program and points the reset vector at it, so reset() will
+    // start running it
+    pub fn load_program_at_reset(&mut self, start: u16, bytes: &[u8]) -> Result<(), CpuError> {
+        self.load_program(start, bytes)?;
+        self.set_reset_vector(start);
+        Ok(())
+    }
+
+    // Byte sized shorthands for the two accessors above, for tests and front ends
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.set_memory_at_address(address, value);
+    }
+
+    pub fn peek(&self, address: u16) -> u8 {
+        self.inspect_memory_at_address(address)
+    }
+
+    // Little-endian word, the peek to go with write16
+    pub fn peek16(&self, address: u16) -> u16 {
+        self.read16(address)
+    }
+
+    // Mark a range as read-only (or writable again), handy for catching
+    // something trampling over the zero page or the stack
+    pub fn protect_range(&mut self, range: Range<u16>, on: bool) {
+        if on {
+            if !self.protected.contains(&range) {
+                self.protected.push(range);
+            }
+        } else {
+            self.protected.retain(|r| *r != range);
+        }
+    }
+
+    // Reports every access of the given kind anywhere in the range as an
+    // event with the exact address, EG. to keep an eye on a whole buffer
+    pub fn add_watch_region(&mut self, range: Range<u16>, kind: WatchKind) {
+        self.watch_regions.push((range, kind));
+    }
+
+    pub fn remove_watch_region(&mut self, range: Range<u16>) {
+        self.watch_regions.retain(|(r, _)| *r != range);
+    }
+
+    fn watched(&self, address: u16, write: bool) -> bool {
+        self.watch_regions.iter().any(|(r, kind)| r.contains(&address) && kind.matches(write))
+    }
+
+    // Hands over everything that happened since the last call
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    // Assert the NMI line, it will be serviced before the next instruction
+    // or hijack the vector fetch of a BRK/IRQ sequence that is in progress
+    pub fn request_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    // Takes an NMI straight away, the interrupt flag can't hold it off
+    pub fn nmi(&mut self) {
+        self.interrupt(NMI_VECTOR, false);
+        self.cycles += INTERRUPT_CYCLES as u64;
+    }
+
+    // Assert the NMI line once the next opcode has been fetched, too late for
+    // it to be taken first. During a BRK it hijacks the vector fetch instead
+    pub fn request_nmi_after_fetch(&mut self) {
+        self.nmi_after_fetch = true;
+    }
+
+    // Assert the IRQ line, only serviced while the interrupt flag is clear
+    pub fn request_irq(&mut self) {
+        self.irq_pending = true;
+    }
+
+    // Takes an IRQ straight away instead of at the next instruction boundary,
+    // does nothing while the interrupt flag is set
+    pub fn irq(&mut self) {
+        if !self.registers.sr.interrupt {
+            self.interrupt(IRQ_VECTOR, false);
+            self.cycles += INTERRUPT_CYCLES as u64;
+        }
+    }
+
+    // Which interrupt, if any, will be taken at the next instruction boundary.
+    // NMI takes priority and an IRQ has to wait for the interrupt flag to clear
+    pub fn would_service_interrupt(&self) -> Option<InterruptKind> {
+        if self.nmi_pending {
+            Some(InterruptKind::Nmi)
+        } else if self.irq_pending && !self.delayed_interrupt_flag.unwrap_or(self.registers.sr.interrupt) {
+            Some(InterruptKind::Irq)
+        } else {
+            None
+        }
+    }
+
+    // Called at each instruction boundary, hands back the cycles spent
+    // taking an interrupt if one was taken. BRK isn't charged in here or in
+    // interrupt(), its 7 cycles are already in the cycle table
+    pub fn poll_interrupts(&mut self) -> u8 {
+        match self.would_service_interrupt() {
+            Some(InterruptKind::Nmi) => self.interrupt(NMI_VECTOR, false),
+            Some(InterruptKind::Irq) => self.interrupt(IRQ_VECTOR, false),
+            None => return 0,
+        }
+        self.cycles += INTERRUPT_CYCLES as u64;
+        INTERRUPT_CYCLES
+    }
+
+    // Shared sequence for BRK, IRQ and NMI. The vector is only picked once the
+    // stack pushes are done, so an NMI asserted during a BRK or IRQ hijacks it
+    // and the handler at the NMI vector runs instead, like on the NMOS 6502
+    pub fn interrupt(&mut self, vector: u16, sbreak: bool) {
+        let pc = self.registers.pc;
+        self.push_to_stack((pc >> 8) as u8);
+        self.push_to_stack(pc as u8);
+        let mut status = self.registers.sr;
+        status.ignored = true;
+        status.sbreak = sbreak;
+        self.push_to_stack(u8::from(status));
+        self.registers.sr.interrupt = true;
+
+        let vector = if self.nmi_pending {
+            self.nmi_pending = false;
+            NMI_VECTOR
+        } else {
+            if vector == IRQ_VECTOR && !sbreak {
+                self.irq_pending = false;
             }
+            vector
+        };
+        self.registers.pc = self.read16(vector);
+    }
+
+    // The instruction that runs the opcode on this variant, if there is one.
+    // Takes the instruction sets separately so the result doesn't keep self
+    // borrowed while the instruction runs
+    fn find_instruction<'a>(&self, opcode: u8, instructions: &'a [Box<dyn Instruction<B>>],
+                            cmos_instructions: &'a [Box<dyn Instruction<B>>]) -> Option<&'a dyn Instruction<B>> {
+        if !self.has_ror && opcodes::mnemonic(opcode) == "ROR" {
+            return None;
+        }
+        let legal = !opcodes::is_illegal(opcode);
+        let nmos = self.dispatch[opcode as usize].map(|i| instructions[i].as_ref());
+        match self.variant {
+            Variant::Cmos => self.cmos_dispatch[opcode as usize]
+                .map(|i| cmos_instructions[i].as_ref())
+                .or_else(|| nmos.filter(|_| legal)),
+            Variant::Nmos => nmos.filter(|_| legal || self.illegal_opcodes),
+        }
+    }
+
+    pub fn is_implemented(&self, opcode: u8) -> bool {
+        self.find_instruction(opcode, &self.instructions, &self.cmos_instructions).is_some()
+    }
+
+    pub fn execute_instruction(&mut self, opcode: &u8) {
+        // The instruction needs all of self, so the sets are moved out while it
+        // runs and put back after. Taking an empty Vec doesn't allocate, which
+        // keeps this cheaper than cloning an Arc every step
+        let instructions = std::mem::take(&mut self.instructions);
+        let cmos_instructions = std::mem::take(&mut self.cmos_instructions);
+        let instruction = self.find_instruction(*opcode, &instructions, &cmos_instructions);
+        let increment = instruction.map(|i| i.execute(opcode, self));
+        self.instructions = instructions;
+        self.cmos_instructions = cmos_instructions;
+        match increment {
+            Some(true) => { self.registers.increment_pc(); }
+            Some(false) => {}
+            None => {
+                self.hit_unknown.push((self.registers.pc, *opcode));
+                match self.unknown_opcode {
+                    UnknownOpcode::Panic => panic!("An unknown instruction was called"),
+                    UnknownOpcode::TreatAsNop => {
+                        let (_, mode) = opcodes::decode_for(*opcode, self.variant);
+                        self.registers.increment_pc_by(1 + mode.operand_length());
+                    }
+                }
+            },
+        }
+    }
+}
+
+// Every address where the two CPUs' memory differs, with a's value then b's
+pub fn memory_diff<A: Bus, B: Bus>(a: &CPU<A>, b: &CPU<B>) -> Vec<(u16, u8, u8)> {
+    // Sharing the same memory, locking it twice would deadlock
+    if Arc::ptr_eq(&a.memory, &b.memory) {
+        return Vec::new();
+    }
+    let a_memory = a.memory.lock().expect("Failed to lock memory");
+    let b_memory = b.memory.lock().expect("Failed to lock memory");
+    a_memory.iter()
+        .zip(b_memory.iter())
+        .enumerate()
+        .filter(|(_, (x, y))| **x != **y)
+        .map(|(address, (x, y))| (address as u16, (*x), (*y)))
+        .collect()
+}
+
+// Where run_program puts the program
+pub const PROGRAM_START: u16 = 0x0600;
+// How many instructions run_program gives a program before giving up on it
+pub const PROGRAM_INSTRUCTION_LIMIT: u64 = 1_000_000;
+
+// Quick way to try out a few bytes, loads them at PROGRAM_START and runs them
+// until they loop on themselves (EG. end with JMP *) or hit the limit
+pub fn run_program(bytes: &[u8]) -> CPU {
+    let mut cpu = CPU::new();
+    for (offset, byte) in bytes.iter().enumerate() {
+        cpu.set_memory_at_address(PROGRAM_START.wrapping_add(offset as u16), *byte);
+    }
+    cpu.registers.pc = PROGRAM_START;
+    cpu.stop_on_self_loop = true;
+    cpu.run_bounded(PROGRAM_INSTRUCTION_LIMIT);
+    cpu
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A CPU with the program loaded at $0200 and PC pointing at it
+    fn cpu_with(program: &[u8]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_program(0x0200, program).unwrap();
+        cpu.set_pc(0x0200);
+        cpu
+    }
+
+    #[test]
+    fn nmi_during_brk_takes_the_nmi_vector() {
+        let mut cpu = cpu_with(&[0x00]);
+        cpu.set_irq_vector(0x9000);
+        cpu.set_nmi_vector(0xA000);
+        cpu.request_nmi_after_fetch();
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0xA000);
+        // BRK still did its pushes, so the status on the stack has B set
+        assert_eq!(cpu.peek_stack(0) & 0x10, 0x10);
+        assert!(!cpu.nmi_pending);
+    }
+
+    #[test]
+    fn nmi_before_brk_is_taken_first() {
+        let mut cpu = cpu_with(&[0x00]);
+        cpu.set_irq_vector(0x9000);
+        cpu.set_nmi_vector(0xA000);
+        cpu.request_nmi();
+        cpu.step();
+        assert_eq!(cpu.peek_stack(0) & 0x10, 0);
+        assert_eq!(le_word(cpu.peek_stack(1), cpu.peek_stack(2)), 0x0200);
+    }
+
+    #[test]
+    fn protected_write_raises_an_event_and_is_dropped() {
+        let mut cpu = CPU::new();
+        cpu.poke(0x0010, 0x11);
+        cpu.protect_range(0x0000..0x0100, true);
+        cpu.poke(0x0010, 0x22);
+        assert_eq!(cpu.peek(0x0010), 0x11);
+        assert_eq!(cpu.take_events(), vec![Event::ProtectedWrite { address: 0x0010, value: 0x22 }]);
+        cpu.protect_range(0x0000..0x0100, false);
+        cpu.poke(0x0010, 0x22);
+        assert_eq!(cpu.peek(0x0010), 0x22);
+    }
+
+    #[test]
+    fn next_pc_of_a_load_is_past_its_operand() {
+        let cpu = cpu_with(&[0xA9, 0x00]);
+        assert_eq!(cpu.next_pc(), NextPc::Fixed(0x0202));
+    }
+
+    #[test]
+    fn next_pc_of_a_branch_has_both_outcomes() {
+        let cpu = cpu_with(&[0xD0, 0x10]);
+        assert_eq!(cpu.next_pc(), NextPc::Branch { taken: 0x0212, not_taken: 0x0202 });
+    }
+
+    #[test]
+    fn soft_reset_keeps_ram_and_hard_reset_clears_it() {
+        let mut cpu = cpu_with(&[0xA9, 0x01]);
+        cpu.ram_init = RamInit::Zero;
+        cpu.poke(0x0300, 0x42);
+        cpu.step();
+        cpu.soft_reset();
+        assert_eq!(cpu.peek(0x0300), 0x42);
+        cpu.hard_reset();
+        assert_eq!(cpu.peek(0x0300), 0x00);
+        // Counted from zero again, only the reset sequence itself is on there
+        assert_eq!(cpu.cycles(), 7);
+        assert_eq!(cpu.instructions_executed(), 0);
+    }
+
+    #[test]
+    fn run_bounded_stops_a_program_that_never_ends() {
+        let mut cpu = cpu_with(&[0x4C, 0x00, 0x02]);
+        let result = cpu.run_bounded(10);
+        assert_eq!(result.reason, HaltReason::InstructionLimit);
+        assert_eq!(result.instructions, 10);
+        assert_eq!(cpu.registers.pc, 0x0200);
+    }
+
+    #[test]
+    fn vectors_are_written_little_endian() {
+        let mut cpu = CPU::new();
+        cpu.set_irq_vector(0xABCD);
+        assert_eq!(cpu.peek(0xFFFE), 0xCD);
+        assert_eq!(cpu.peek(0xFFFF), 0xAB);
+    }
+
+    #[test]
+    fn memory_diff_finds_the_one_difference() {
+        let a = CPU::new();
+        let mut b = CPU::new();
+        b.poke(0x1234, 0x56);
+        assert_eq!(memory_diff(&a, &b), vec![(0x1234, a.peek(0x1234), 0x56)]);
+    }
+
+    #[test]
+    fn step_returns_the_cycles_taken() {
+        let mut cpu = cpu_with(&[0xA9, 0x00, 0xBD, 0xFF, 0x30]);
+        assert_eq!(cpu.step(), 2);
+        cpu.registers.x = 1;
+        assert_eq!(cpu.step(), 5);
+    }
+
+    #[test]
+    fn operand_bytes_are_little_endian() {
+        assert_eq!(le_word(0x34, 0x12), 0x1234);
+        let mut cpu = cpu_with(&[0xAD, 0x34, 0x12]);
+        cpu.poke(0x1234, 0x99);
+        cpu.step();
+        assert_eq!(cpu.registers.ac, 0x99);
+    }
+
+    #[test]
+    fn zero_page_indexing_wraps() {
+        let mut cpu = cpu_with(&[0xB5, 0xFF]);
+        cpu.registers.x = 2;
+        assert_eq!(cpu.compute_effective_address(Mode::ZeropageX), (0x0001, false));
+        assert_eq!(cpu.registers.pc, 0x0201);
+    }
+
+    #[test]
+    fn absolute_x_reports_a_page_cross() {
+        let mut cpu = cpu_with(&[0xBD, 0xFF, 0x30]);
+        cpu.registers.x = 1;
+        assert_eq!(cpu.compute_effective_address(Mode::AbsoluteX), (0x3100, true));
+        assert_eq!(cpu.registers.pc, 0x0202);
+    }
+
+    #[test]
+    fn indirect_y_adds_y_to_the_pointer() {
+        let mut cpu = cpu_with(&[0xB1, 0x10]);
+        cpu.poke(0x0010, 0x80);
+        cpu.poke(0x0011, 0x12);
+        cpu.registers.y = 0x90;
+        assert_eq!(cpu.compute_effective_address(Mode::IndirectY), (0x1310, true));
+    }
+
+    // Reads and writes lock the memory in place, nothing along the way takes
+    // a clone of the Arc
+    #[test]
+    fn memory_access_doesnt_clone_the_arc() {
+        let mut cpu = cpu_with(&[0xAD, 0x00, 0x03, 0x8D, 0x01, 0x03]);
+        assert_eq!(Arc::strong_count(&cpu.memory), 1);
+        for _ in 0..1000 {
+            cpu.set_pc(0x0200);
+            cpu.step();
+            cpu.step();
+            let value = cpu.get_memory_at_address(0x0301);
+            cpu.set_memory_at_address(0x0302, value);
+            assert_eq!(Arc::strong_count(&cpu.memory), 1);
+        }
+        // Sharing it is what bumps the count
+        let shared = Arc::clone(&cpu.memory);
+        assert_eq!(Arc::strong_count(&shared), 2);
+    }
+
+    #[test]
+    fn trace_file_has_a_line_per_instruction() {
+        let path = std::env::temp_dir().join(format!("grey6502-trace-{}.txt", std::process::id()));
+        let mut cpu = cpu_with(&[0xA9, 0x01, 0x8D, 0x00, 0x03, 0xEA, 0x4C, 0x06, 0x02]);
+        cpu.set_speed(std::time::Duration::ZERO);
+        cpu.stop_on_self_loop = true;
+        cpu.trace_to_file(&path).unwrap();
+        cpu.run();
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(trace.lines().count() as u64, cpu.instructions_executed());
+        assert_eq!(cpu.instructions_executed(), 3);
+    }
+
+    #[test]
+    fn indexed_stores_always_pay_the_page_cycle() {
+        let cycles = |opcode: u8, x: u8| {
+            let mut cpu = cpu_with(&[opcode, 0xFF, 0x30]);
+            cpu.registers.x = x;
+            cpu.step()
         };
-        if instruction.execute(opcode, self) {
-            self.registers.increment_pc();
+        assert_eq!(cycles(0xBD, 1), 5);
+        assert_eq!(cycles(0x9D, 1), 5);
+        assert_eq!(cycles(0x9D, 0), 5);
+    }
+
+    // Somewhere for a trace to go that the test can still read afterwards
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn trace_shows_operands_as_fetched_when_overwritten() {
+        // STA $0201 writes over its own operand
+        let mut cpu = cpu_with(&[0x8D, 0x01, 0x02]);
+        let trace = SharedBuffer::default();
+        cpu.set_text_trace(Some(Box::new(trace.clone())));
+        cpu.registers.ac = 0xFF;
+        cpu.step();
+        assert_eq!(cpu.peek(0x0201), 0xFF);
+        assert!(trace.text().starts_with("0200  8D 01 02  STA $0201"));
+    }
+
+    #[test]
+    fn irq_waits_one_instruction_after_cli() {
+        let mut cpu = cpu_with(&[0x58, 0xEA, 0xEA]);
+        cpu.set_irq_vector(0x9000);
+        cpu.registers.sr.interrupt = true;
+        cpu.request_irq();
+        cpu.step();
+        // The NOP after CLI still runs with the old I
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0x0202);
+        assert!(cpu.irq_pending);
+        // Taken at the next boundary, the handler's first NOP runs in the same step
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0x9001);
+        assert_eq!(le_word(cpu.peek_stack(1), cpu.peek_stack(2)), 0x0202);
+    }
+
+    #[test]
+    fn halted_cpu_stays_put_until_reset() {
+        let mut cpu = cpu_with(&[0xEA, 0xEA]);
+        cpu.set_reset_vector(0x0200);
+        cpu.halt(HaltReason::ResultTrap);
+        for _ in 0..3 {
+            assert_eq!(cpu.try_step(), Err(CpuError::Halted { pc: 0x0200 }));
+            assert_eq!(cpu.step(), 0);
+        }
+        assert_eq!(cpu.registers.pc, 0x0200);
+        assert_eq!(cpu.cycles(), 0);
+        cpu.reset();
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.try_step(), Ok(2));
+        assert_eq!(cpu.registers.pc, 0x0201);
+    }
+
+    #[test]
+    fn run_bounded_reports_a_self_loop() {
+        // LDA #$05, NOP, then JMP to itself
+        let mut cpu = cpu_with(&[0xA9, 0x05, 0xEA, 0x4C, 0x03, 0x02]);
+        cpu.stop_on_self_loop = true;
+        let result = cpu.run_bounded(100);
+        assert_eq!(result, RunResult {
+            reason: HaltReason::SelfLoop(0x0203),
+            cycles: 4,
+            instructions: 2,
+            final_pc: 0x0203,
+        });
+        assert_eq!(cpu.registers.ac, 0x05);
+    }
+
+    #[test]
+    fn status_byte_round_trips() {
+        for byte in 0..=255u8 {
+            assert_eq!(u8::from(StatRegister::from(byte)), byte, "status {:02X}", byte);
+        }
+    }
+
+    #[test]
+    fn status_register_round_trips() {
+        // Every combination of the eight flags, bit n of combo is flag n
+        for combo in 0..=255u16 {
+            let bit = |n: u16| combo & (1 << n) != 0;
+            let sr = StatRegister {
+                negative: bit(0),
+                overflow: bit(1),
+                ignored: bit(2),
+                sbreak: bit(3),
+                decimal: bit(4),
+                interrupt: bit(5),
+                zero: bit(6),
+                carry: bit(7),
+            };
+            assert_eq!(StatRegister::from(u8::from(sr)), sr);
+        }
+    }
+
+    #[test]
+    fn push_lands_in_page_one() {
+        let mut cpu = CPU::new();
+        cpu.set_sp(0xFF);
+        cpu.push_to_stack(0x42);
+        assert_eq!(cpu.get_memory_at_address(0x01FF), 0x42);
+        assert_eq!(cpu.registers.sp, 0xFE);
+        // SP wraps back round to $FF rather than leaving the page
+        cpu.set_sp(0x00);
+        cpu.push_to_stack(0x43);
+        assert_eq!(cpu.get_memory_at_address(0x0100), 0x43);
+        assert_eq!(cpu.registers.sp, 0xFF);
+        assert_eq!(cpu.pull_from_stack(), 0x43);
+    }
+
+    #[test]
+    fn taking_an_irq_costs_seven_cycles() {
+        let mut cpu = cpu_with(&[0xEA]);
+        cpu.set_irq_vector(0x9000);
+        cpu.registers.sr.interrupt = false;
+        cpu.irq();
+        assert_eq!(cpu.cycles(), 7);
+        // Held off by I, so nothing is charged
+        cpu.irq();
+        assert_eq!(cpu.cycles(), 7);
+
+        // Serviced at the boundary, then the handler's NOP runs in the same step
+        let mut cpu = cpu_with(&[0xEA]);
+        cpu.set_irq_vector(0x9000);
+        cpu.registers.sr.interrupt = false;
+        cpu.request_irq();
+        assert_eq!(cpu.step(), 7 + 2);
+        assert_eq!(cpu.cycles(), 9);
+    }
+
+    #[test]
+    fn taking_an_nmi_costs_seven_cycles() {
+        let mut cpu = cpu_with(&[0xEA]);
+        cpu.set_nmi_vector(0xA000);
+        cpu.registers.sr.interrupt = true;
+        cpu.nmi();
+        assert_eq!(cpu.cycles(), 7);
+        assert_eq!(cpu.registers.pc, 0xA000);
+
+        let mut cpu = cpu_with(&[0xEA]);
+        cpu.set_nmi_vector(0xA000);
+        cpu.request_nmi();
+        assert_eq!(cpu.step(), 7 + 2);
+        assert_eq!(cpu.cycles(), 9);
+    }
+
+    #[test]
+    fn loading_past_the_top_of_memory_fails() {
+        let mut cpu = CPU::new();
+        assert_eq!(cpu.load_program(0xFFFF, &[0x01, 0x02]),
+                   Err(CpuError::SegmentOverflow { start: 0xFFFF, length: 2 }));
+        // Nothing was written
+        assert_eq!(cpu.peek(0xFFFF), 0xEA);
+        assert_eq!(cpu.peek(0x0000), 0xEA);
+        // Right up to $FFFF is fine
+        assert_eq!(cpu.load_program(0xFFFE, &[0x01, 0x02]), Ok(()));
+        assert_eq!(cpu.peek16(0xFFFE), 0x0201);
+    }
+
+    #[test]
+    fn try_step_checks_the_opcode_an_interrupt_leads_to() {
+        let mut cpu = cpu_with(&[0xEA]);
+        cpu.set_irq_vector(0x9000);
+        // Nothing implements $02 on the 65C02, so the handler can't run
+        cpu.variant = Variant::Cmos;
+        cpu.poke(0x9000, 0x02);
+        cpu.registers.sr.interrupt = false;
+        cpu.request_irq();
+        assert_eq!(cpu.try_step(), Err(CpuError::UnknownOpcode { opcode: 0x02, pc: 0x9000 }));
+        // Checked before the interrupt was taken, so none of it happened
+        assert_eq!(cpu.registers.pc, 0x0200);
+        assert_eq!(cpu.cycles(), 0);
+        assert!(cpu.irq_pending);
+        // Once the handler is fixed the same step goes through, 7 cycles for
+        // the interrupt and 2 for the handler's NOP
+        cpu.poke(0x9000, 0xEA);
+        assert_eq!(cpu.try_step(), Ok(9));
+        assert_eq!(cpu.cycles(), 9);
+        assert_eq!(cpu.registers.pc, 0x9001);
+    }
+
+    #[test]
+    fn trace_after_reset_matches_nestest() {
+        let mut cpu = CPU::new();
+        cpu.load_program_at_reset(0xC000, &[0xA9, 0x50, 0xEA]).unwrap();
+        cpu.reset();
+        let trace = SharedBuffer::default();
+        cpu.set_text_trace(Some(Box::new(trace.clone())));
+        cpu.step();
+        cpu.step();
+        let text = trace.text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0],
+            "C000  A9 50     LDA #$50                        A:00 X:00 Y:00 P:24 SP:FD CYC:7");
+        // Each line has the registers from before its instruction ran
+        assert!(lines[1].starts_with("C002  EA        NOP"));
+        assert!(lines[1].ends_with("A:50 X:00 Y:00 P:24 SP:FD CYC:9"));
+    }
+
+    #[test]
+    fn running_past_the_code_range_raises_an_event() {
+        // LDA #$01 is all the code there is, the NOP after it is RAM fill
+        let mut cpu = cpu_with(&[0xA9, 0x01]);
+        cpu.set_code_range(Some(0x0200..0x0202));
+        cpu.step();
+        assert_eq!(cpu.take_events(), vec![]);
+        cpu.step();
+        assert_eq!(cpu.take_events(), vec![Event::LeftCodeRange { pc: 0x0202 }]);
+
+        let mut cpu = cpu_with(&[0xA9, 0x01]);
+        cpu.mark_data_range(0x0202..0x0210);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.take_events(), vec![Event::ExecutedData { pc: 0x0202 }]);
+    }
+
+    #[test]
+    fn relative_targets_count_from_the_next_instruction() {
+        let mut cpu = cpu_with(&[0xD0, 0x05]);
+        assert_eq!(cpu.compute_effective_address(Mode::Relative), (0x0207, false));
+        assert_eq!(cpu.registers.pc, 0x0201);
+        // Backwards into the page below
+        let mut cpu = cpu_with(&[0xD0, 0xFB]);
+        assert_eq!(cpu.compute_effective_address(Mode::Relative), (0x01FD, true));
+    }
+
+    #[test]
+    fn annotated_listing_labels_lines_and_operands() {
+        // JSR init, JMP to itself, then init is just an RTS
+        let cpu = cpu_with(&[0x20, 0x06, 0x02, 0x4C, 0x03, 0x02, 0x60]);
+        let mut symbols = HashMap::new();
+        symbols.insert(0x0206, String::from("init"));
+        let listing = cpu.annotated_listing(0x0200, 0x0207, &symbols);
+        assert_eq!(listing, "\
+0200  20 06 02  JSR init
+0203  4C 03 02  JMP $0203
+init:
+0206  60        RTS
+");
+    }
+
+    #[test]
+    fn writing_to_the_result_trap_halts_with_the_exit_code() {
+        // LDA #$00, STA $6000, then spin
+        let mut cpu = cpu_with(&[0xA9, 0x00, 0x8D, 0x00, 0x60, 0x4C, 0x05, 0x02]);
+        cpu.set_result_trap(0x6000);
+        let result = cpu.run_bounded(10);
+        assert_eq!(result.reason, HaltReason::ResultTrap);
+        assert_eq!(result.instructions, 2);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.exit_code(), Some(0));
+    }
+
+    #[test]
+    fn current_mode_decodes_the_opcode_at_pc() {
+        let mut cpu = cpu_with(&[0xB1, 0x20, 0x0A]);
+        assert_eq!(cpu.current_mode(), Mode::IndirectY);
+        cpu.set_pc(0x0202);
+        assert_eq!(cpu.current_mode(), Mode::A);
+    }
+
+    #[test]
+    fn binary_trace_reads_back_what_ran() {
+        let mut cpu = cpu_with(&[0xA9, 0x05, 0xEA, 0xA9, 0x80]);
+        let trace = SharedBuffer::default();
+        cpu.set_binary_trace(Some(Box::new(trace.clone())));
+        for _ in 0..3 {
+            cpu.step();
+        }
+        let bytes = trace.0.borrow().clone();
+        assert_eq!(bytes.len(), 3 * TraceRecord::SIZE);
+        let records = crate::trace::read_binary_trace(&mut &bytes[..]).unwrap();
+        let steps: Vec<_> = records.iter().map(|r| (r.pc, r.opcode, r.a, r.cycle)).collect();
+        assert_eq!(steps, vec![(0x0200, 0xA9, 0x00, 0), (0x0202, 0xEA, 0x05, 2), (0x0203, 0xA9, 0x05, 4)]);
+        let text = crate::trace::binary_trace_to_text(&mut &bytes[..]).unwrap();
+        assert_eq!(text.lines().next(), Some("0200  A9  A:00 X:00 Y:00 P:20 SP:00 CYC:0"));
+    }
+
+    #[test]
+    fn set_ac_updates_n_and_z() {
+        let mut cpu = CPU::new();
+        cpu.set_ac(0x80);
+        assert_eq!(cpu.registers().ac, 0x80);
+        assert!(cpu.registers().sr.negative);
+        assert!(!cpu.registers().sr.zero);
+        cpu.set_ac(0x00);
+        assert!(!cpu.registers().sr.negative);
+        assert!(cpu.registers().sr.zero);
+    }
+
+    #[test]
+    fn run_stops_at_a_jmp_to_itself() {
+        // LDA #$01 then JMP *
+        let mut cpu = cpu_with(&[0xA9, 0x01, 0x4C, 0x02, 0x02]);
+        cpu.set_speed(std::time::Duration::ZERO);
+        cpu.stop_on_self_loop = true;
+        assert_eq!(cpu.run(), HaltReason::SelfLoop(0x0202));
+        assert_eq!(cpu.registers().ac, 0x01);
+        assert_eq!(cpu.instructions_executed(), 1);
+    }
+
+    #[test]
+    fn run_program_runs_until_the_self_loop() {
+        // LDA #$05 then JMP *
+        let cpu = run_program(&[0xA9, 0x05, 0x4C, 0x02, 0x06]);
+        assert_eq!(cpu.registers().ac, 0x05);
+        assert_eq!(cpu.registers().pc, PROGRAM_START + 2);
+        assert_eq!(cpu.instructions_executed(), 1);
+    }
+
+    #[test]
+    fn overflow_pin_sets_v_for_bvs() {
+        let mut cpu = cpu_with(&[0x70, 0x04]);
+        assert!(!cpu.registers().sr.overflow);
+        cpu.set_overflow_pin();
+        assert!(cpu.registers().sr.overflow);
+        cpu.step();
+        assert_eq!(cpu.registers().pc, 0x0206);
+    }
+
+    #[test]
+    fn trace_range_only_traces_inside_it() {
+        // LDA #$01, then NOPs at $0202, $0203 and $0204
+        let mut cpu = cpu_with(&[0xA9, 0x01, 0xEA, 0xEA, 0xEA]);
+        let trace = SharedBuffer::default();
+        cpu.set_text_trace(Some(Box::new(trace.clone())));
+        cpu.set_trace_range(Some(0x0202..0x0204));
+        for _ in 0..4 {
+            cpu.step();
+        }
+        let text = trace.text();
+        let addresses: Vec<&str> = text.lines().map(|line| &line[..4]).collect();
+        assert_eq!(addresses, vec!["0202", "0203"]);
+    }
+
+    #[test]
+    fn run_with_callback_stops_when_told_to() {
+        // JMP to itself, it would never stop on its own
+        let mut cpu = cpu_with(&[0x4C, 0x00, 0x02]);
+        let mut calls = 0;
+        cpu.run_with_callback(|_| {
+            calls += 1;
+            if calls == 10 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        });
+        assert_eq!(calls, 10);
+        assert_eq!(cpu.instructions_executed(), 10);
+    }
+
+    #[test]
+    fn three_byte_instruction_advances_pc_by_three() {
+        let mut cpu = cpu_with(&[0xAD, 0x34, 0x12, 0xEA]);
+        // The helper leaves PC on the last operand byte, the step moves off it
+        assert_eq!(cpu.fetch_operand_word(), 0x1234);
+        assert_eq!(cpu.registers().pc, 0x0202);
+        cpu.set_pc(0x0200);
+        cpu.step();
+        assert_eq!(cpu.registers().pc, 0x0203);
+    }
+
+    #[test]
+    fn would_service_interrupt_respects_i_and_nmi_priority() {
+        let mut cpu = cpu_with(&[0xEA]);
+        cpu.set_flag(Flag::Interrupt, true);
+        cpu.request_irq();
+        assert_eq!(cpu.would_service_interrupt(), None);
+        // An NMI goes first whatever I says
+        cpu.request_nmi();
+        assert_eq!(cpu.would_service_interrupt(), Some(InterruptKind::Nmi));
+        cpu.set_flag(Flag::Interrupt, false);
+        assert_eq!(cpu.would_service_interrupt(), Some(InterruptKind::Nmi));
+        cpu.nmi_pending = false;
+        assert_eq!(cpu.would_service_interrupt(), Some(InterruptKind::Irq));
+    }
+
+    #[test]
+    fn reset_counters_measures_just_the_loop() {
+        // LDA #$01, then a JMP to itself as the loop being measured
+        let mut cpu = cpu_with(&[0xA9, 0x01, 0x4C, 0x02, 0x02]);
+        cpu.step();
+        assert_eq!((cpu.cycles(), cpu.instructions_executed()), (2, 1));
+        cpu.reset_counters();
+        assert_eq!((cpu.cycles(), cpu.instructions_executed()), (0, 0));
+        cpu.run_bounded(5);
+        assert_eq!((cpu.cycles(), cpu.instructions_executed()), (15, 5));
+        // Registers and memory are left as they were
+        assert_eq!(cpu.registers().ac, 0x01);
+        assert_eq!(cpu.peek(0x0200), 0xA9);
+    }
+
+    #[test]
+    fn initial_sp_is_where_the_first_push_lands() {
+        let mut cpu = CPU::new();
+        cpu.initial_sp = Some(0xF0);
+        // PHA
+        cpu.load_program_at_reset(0x0200, &[0x48]).unwrap();
+        cpu.reset();
+        assert_eq!(cpu.registers().sp, 0xF0);
+        cpu.set_ac(0x42);
+        cpu.step();
+        assert_eq!(cpu.peek(0x01F0), 0x42);
+        assert_eq!(cpu.registers().sp, 0xEF);
+    }
+
+    #[test]
+    fn watch_region_reports_the_exact_address() {
+        // STA $0305, STA $0310, LDA $0305
+        let mut cpu = cpu_with(&[0x8D, 0x05, 0x03, 0x8D, 0x10, 0x03, 0xAD, 0x05, 0x03]);
+        cpu.add_watch_region(0x0300..0x0310, WatchKind::Write);
+        cpu.set_ac(0x42);
+        cpu.step();
+        assert_eq!(cpu.take_events(), vec![Event::WatchWrite { address: 0x0305, value: 0x42 }]);
+        // Just past the end, then a read which this region doesn't watch
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.take_events(), vec![]);
+    }
+
+    #[test]
+    fn execute_opcode_runs_lda_immediate() {
+        let mut cpu = CPU::new();
+        cpu.set_pc(0x0300);
+        assert_eq!(cpu.execute_opcode(0xA9, &[0x7F]), 2);
+        assert_eq!(cpu.registers().ac, 0x7F);
+        assert!(!cpu.registers().sr.negative);
+        assert!(!cpu.registers().sr.zero);
+        assert_eq!(cpu.registers().pc, 0x0302);
+    }
+
+    #[test]
+    fn call_stack_lists_nested_jsrs_innermost_first() {
+        let mut cpu = cpu_with(&[0x20, 0x10, 0x02]);
+        // The first subroutine calls a second one at $0220
+        cpu.load_program(0x0210, &[0x20, 0x20, 0x02]).unwrap();
+        cpu.set_sp(0xFF);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers().pc, 0x0220);
+        assert_eq!(cpu.call_stack(), vec![0x0213, 0x0203]);
+    }
+
+    #[test]
+    fn running_into_unwritten_ram_warns_once() {
+        let mut cpu = cpu_with(&[0xA9, 0x01]);
+        cpu.warn_on_execute_uninitialized = true;
+        cpu.step();
+        assert_eq!(cpu.take_events(), vec![]);
+        // $0202 is still the NOP fill
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.take_events(), vec![Event::ExecutedUninitialized { pc: 0x0202 }]);
+    }
+
+    #[test]
+    fn every_flag_toggles_its_own_bit() {
+        let mut bits = 0;
+        for flag in Flag::all() {
+            let mut sr = StatRegister::from(0);
+            sr.set(flag, true);
+            assert!(sr.get(flag));
+            let bit = u8::from(sr);
+            assert_eq!(bit.count_ones(), 1, "{:?}", flag);
+            bits |= bit;
+            sr.set(flag, false);
+            assert!(!sr.get(flag));
+            assert_eq!(u8::from(sr), 0);
         }
+        // Everything but the unused bit 5
+        assert_eq!(bits, 0xDF);
+    }
+
+    #[test]
+    fn try_run_returns_at_a_self_loop() {
+        // LDA #$05 then JMP *
+        let mut cpu = cpu_with(&[0xA9, 0x05, 0x4C, 0x02, 0x02]);
+        let result = cpu.try_run(1000).unwrap();
+        assert_eq!(result.reason, HaltReason::SelfLoop(0x0202));
+        assert_eq!((result.cycles, result.instructions), (2, 1));
+        assert_eq!(cpu.registers().ac, 0x05);
+    }
+
+    // A mapper switching between two 16K banks at $8000, writing there
+    // picks the bank
+    struct Banked {
+        banks: [[u8; 0x4000]; 2],
+        bank: usize,
+    }
+
+    impl Bus for Banked {
+        fn handles(&self, address: u16) -> bool {
+            (0x8000..0xC000).contains(&address)
+        }
+        fn read(&self, address: u16) -> u8 {
+            self.banks[self.bank][(address - 0x8000) as usize]
+        }
+        fn write(&mut self, _address: u16, value: u8) {
+            self.bank = value as usize & 1;
+        }
+    }
+
+    #[test]
+    fn effective_read_sees_what_a_load_sees() {
+        // LDA $0810, LDA $8001
+        let mut cpu = cpu_with(&[0xAD, 0x10, 0x08, 0xAD, 0x01, 0x80]);
+        cpu.add_mirror(0x0800..0x2000, 0x07FF);
+        cpu.poke(0x0010, 0x42);
+        let mut banks = [[0; 0x4000]; 2];
+        banks[0][1] = 0x11;
+        banks[1][1] = 0x22;
+        cpu.add_device(Banked { banks, bank: 0 });
+        cpu.poke(0x8000, 1);
+        assert_eq!(cpu.effective_read(0x0810), 0x42);
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0x42);
+        assert_eq!(cpu.effective_read(0x8001), 0x22);
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0x22);
+    }
+
+    #[test]
+    fn load_from_reader_copies_everything_in() {
+        let mut cpu = CPU::new();
+        let mut reader = std::io::Cursor::new(vec![0xA9, 0x42, 0x00]);
+        assert_eq!(cpu.load_from_reader(&mut reader, 0x0600).unwrap(), 3);
+        assert_eq!((cpu.peek(0x0600), cpu.peek(0x0601), cpu.peek(0x0602)), (0xA9, 0x42, 0x00));
+        // Nothing is written when it doesn't fit
+        let mut reader = std::io::Cursor::new(vec![0x01, 0x02]);
+        assert!(cpu.load_from_reader(&mut reader, 0xFFFF).is_err());
+        assert_eq!(cpu.peek(0xFFFF), 0xEA);
+    }
+
+    #[test]
+    fn speed_reads_back_what_was_set() {
+        let mut cpu = CPU::new();
+        assert_eq!(cpu.speed(), std::time::Duration::from_millis(750));
+        cpu.set_speed(std::time::Duration::ZERO);
+        assert_eq!(cpu.speed(), std::time::Duration::ZERO);
+        let cpu = CPU::with_bus(RamBus, std::time::Duration::from_micros(1));
+        assert_eq!(cpu.speed(), std::time::Duration::from_micros(1));
+    }
+
+    #[test]
+    fn early_chips_without_ror_reject_it() {
+        let mut cpu = cpu_with(&[0x6A]);
+        assert!(cpu.is_implemented(0x6A));
+        cpu.has_ror = false;
+        assert!(!cpu.is_implemented(0x6A));
+        assert!(!cpu.is_implemented(0x7E));
+        assert_eq!(cpu.try_step(), Err(CpuError::UnknownOpcode { opcode: 0x6A, pc: 0x0200 }));
+        // Everything else still runs
+        assert!(cpu.is_implemented(0x2A));
+    }
+
+    #[test]
+    fn skipped_opcodes_are_listed_in_hit_unknown() {
+        // LDA #$01, an illegal SLO $10 left switched off, LDA #$02
+        let mut cpu = cpu_with(&[0xA9, 0x01, 0x07, 0x10, 0xA9, 0x02]);
+        cpu.unknown_opcode = UnknownOpcode::TreatAsNop;
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.hit_unknown, vec![(0x0202, 0x07)]);
+        assert_eq!(cpu.registers().ac, 0x02);
+        assert_eq!(cpu.registers().pc, 0x0206);
+    }
+
+    #[test]
+    fn run_steps_records_the_handler_after_an_interrupt() {
+        let mut cpu = cpu_with(&[0xA9, 0x01]);
+        cpu.load_program(0x0300, &[0xA9, 0x80, 0xA9, 0x81, 0xA9, 0x82, 0xA9, 0x83, 0xA9, 0x00]).unwrap();
+        cpu.set_nmi_vector(0x0300);
+        cpu.request_nmi();
+        let trace = cpu.run_steps(5);
+        assert_eq!(trace.len(), 5);
+        // The NMI is taken before the first step, so its handler is what ran
+        assert_eq!((trace[0].pc, trace[0].opcode, trace[0].a, trace[0].cycles), (0x0300, 0xA9, 0x80, 9));
+        assert_eq!(trace[4], TraceEntry {
+            pc: 0x0308, opcode: 0xA9, a: 0x00, x: 0x00, y: 0x00,
+            // I from the NMI, Z from the load and bit 5
+            p: 0x26, sp: cpu.registers().sp, cycles: 2,
+        });
+    }
+
+    #[test]
+    fn traps_fire_through_a_mirror() {
+        // "Hi" printed through two different copies of $0010, then the result
+        // written to a copy of $0011
+        let mut cpu = cpu_with(&[
+            0xA9, b'H', 0x8D, 0x10, 0x08,
+            0xA9, b'i', 0x8D, 0x10, 0x10,
+            0xA9, 0x2A, 0x8D, 0x11, 0x18,
+            0xA9, 0x00,
+        ]);
+        cpu.add_mirror(0x0800..0x2000, 0x07FF);
+        cpu.set_trap(0x0010, TrapAction::PrintChar);
+        cpu.set_trap(0x0011, TrapAction::Halt);
+        let result = cpu.run_bounded(20);
+        assert_eq!(result.reason, HaltReason::ResultTrap);
+        assert_eq!(result.instructions, 6);
+        assert_eq!(cpu.take_printed(), b"Hi".to_vec());
+        assert_eq!(cpu.exit_code(), Some(0x2A));
+    }
+
+    #[test]
+    fn memory_map_summary_lists_each_region() {
+        let mut cpu = CPU::new();
+        cpu.add_mirror(0x0800..0x2000, 0x07FF);
+        cpu.add_device(crate::devices::RandomRegister::new(0x00FE, 1));
+        cpu.set_trap(0x6000, TrapAction::Halt);
+        cpu.set_framebuffer(Some(Framebuffer::easy6502()));
+        assert_eq!(cpu.memory_map_summary(), "$0000-$FFFF  RAM\n\
+                                               $0800-$1FFF  mirror of $0000-$07FF\n\
+                                               $00FE-$00FE  random register\n\
+                                               $0200-$05FF  framebuffer\n\
+                                               $6000        trap (Halt)");
+        // An empty framebuffer is left out rather than wrapping round
+        cpu.set_framebuffer(Some(Framebuffer::new(0x0000, 0, 0)));
+        assert!(!cpu.memory_map_summary().contains("framebuffer"));
+    }
+
+    #[test]
+    fn peek16_reads_back_a_written_word() {
+        let mut cpu = CPU::new();
+        cpu.write16(0x0300, 0xBEEF);
+        assert_eq!((cpu.peek(0x0300), cpu.peek(0x0301)), (0xEF, 0xBE));
+        assert_eq!(cpu.peek16(0x0300), 0xBEEF);
+        // The high byte wraps round to the zero page
+        cpu.write16(0xFFFF, 0x1234);
+        assert_eq!(cpu.peek(0x0000), 0x12);
+        assert_eq!(cpu.peek16(0xFFFF), 0x1234);
+    }
+
+    #[test]
+    fn load_segments_loads_code_and_data_apart() {
+        let mut cpu = CPU::new();
+        let code: &[u8] = &[0xAD, 0x00, 0x30];
+        let data: &[u8] = &[0x11, 0x22];
+        cpu.load_segments(&[(0x0200, code), (0x3000, data)]).unwrap();
+        assert_eq!((cpu.peek(0x0200), cpu.peek(0x0201), cpu.peek(0x0202)), (0xAD, 0x00, 0x30));
+        assert_eq!((cpu.peek(0x3000), cpu.peek(0x3001)), (0x11, 0x22));
+        // Segments that only touch end to end don't overlap
+        assert!(cpu.load_segments(&[(0x4000, data), (0x4002, data)]).is_ok());
+    }
+
+    #[test]
+    fn overlapping_segments_fail_without_writing() {
+        let mut cpu = CPU::new();
+        let first: &[u8] = &[0x01, 0x02, 0x03];
+        let second: &[u8] = &[0x04, 0x05];
+        assert_eq!(cpu.load_segments(&[(0x0200, first), (0x0202, second)]),
+                   Err(CpuError::SegmentOverlap { first: 0x0200, second: 0x0202 }));
+        assert_eq!(cpu.peek(0x0200), 0xEA);
+        assert_eq!(cpu.peek(0x0203), 0xEA);
+    }
+
+    #[test]
+    fn irq_takes_the_vector_only_while_interrupts_are_enabled() {
+        let mut cpu = cpu_with(&[0xEA]);
+        cpu.set_irq_vector(0x9000);
+        cpu.registers.sr.interrupt = true;
+        let sp = cpu.registers.sp;
+        cpu.irq();
+        // Masked, nothing pushed and PC left alone
+        assert_eq!(cpu.registers.pc, 0x0200);
+        assert_eq!(cpu.registers.sp, sp);
+
+        cpu.registers.sr.interrupt = false;
+        cpu.registers.sr.carry = true;
+        cpu.irq();
+        assert_eq!(cpu.registers.pc, 0x9000);
+        assert!(cpu.registers.sr.interrupt);
+        // Status with B clear, then the return address
+        assert_eq!(cpu.peek_stack(0), 0x21);
+        assert_eq!(le_word(cpu.peek_stack(1), cpu.peek_stack(2)), 0x0200);
+        assert_eq!(cpu.registers.sp, sp.wrapping_sub(3));
     }
 }
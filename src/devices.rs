@@ -0,0 +1,175 @@
+use crate::{bus::Bus, cpu::CPU, rng::XorShift};
+
+// Hands out a new pseudorandom byte every time it's read. Seeded so a run
+// can be repeated exactly
+pub struct RandomRegister {
+    address: u16,
+    rng: XorShift,
+    // What the last read returned, looking at it doesn't roll a new one
+    last: u8,
+}
+
+impl RandomRegister {
+    pub fn new(address: u16, seed: u64) -> Self {
+        Self { address, rng: XorShift::new(seed), last: 0 }
+    }
+}
+
+impl Bus for RandomRegister {
+    fn name(&self) -> &str {
+        "random register"
+    }
+
+    fn handles(&self, address: u16) -> bool {
+        address == self.address
+    }
+
+    fn read(&self, _address: u16) -> u8 {
+        self.last
+    }
+
+    // Writes are ignored, it's read only
+    fn write(&mut self, _address: u16, _value: u8) {}
+
+    fn has_read_side_effects(&self, _address: u16) -> bool {
+        true
+    }
+
+    fn read_mut(&mut self, _address: u16) -> u8 {
+        self.last = self.rng.next_u8();
+        self.last
+    }
+}
+
+// The zero page inputs from the easy6502 tutorial, a random byte at $FE and
+// the last key pressed at $FF. A front end presses a key by writing its code
+// to $FF, EG. cpu.poke(0xFF, b'w'), and programs write 0 there once handled
+pub struct Easy6502Input {
+    random: RandomRegister,
+    key: u8,
+}
+
+impl Easy6502Input {
+    pub const RANDOM: u16 = 0x00FE;
+    pub const KEY: u16 = 0x00FF;
+
+    pub fn new(seed: u64) -> Self {
+        Self { random: RandomRegister::new(Self::RANDOM, seed), key: 0 }
+    }
+}
+
+impl Bus for Easy6502Input {
+    fn name(&self) -> &str {
+        "easy6502 input"
+    }
+
+    fn handles(&self, address: u16) -> bool {
+        address == Self::RANDOM || address == Self::KEY
+    }
+
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            Self::RANDOM => self.random.read(address),
+            _ => self.key,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if address == Self::KEY {
+            self.key = value;
+        }
+    }
+
+    fn has_read_side_effects(&self, address: u16) -> bool {
+        address == Self::RANDOM
+    }
+
+    fn read_mut(&mut self, address: u16) -> u8 {
+        match address {
+            Self::RANDOM => self.random.read_mut(address),
+            _ => self.key,
+        }
+    }
+}
+
+// The 16 colours of the easy6502 screen, only the low nibble of a pixel
+// byte picks the colour
+pub const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF], [0x88, 0x00, 0x00], [0xAA, 0xFF, 0xEE],
+    [0xCC, 0x44, 0xCC], [0x00, 0xCC, 0x55], [0x00, 0x00, 0xAA], [0xEE, 0xEE, 0x77],
+    [0xDD, 0x88, 0x55], [0x66, 0x44, 0x00], [0xFF, 0x77, 0x77], [0x33, 0x33, 0x33],
+    [0x77, 0x77, 0x77], [0xAA, 0xFF, 0x66], [0x00, 0x88, 0xFF], [0xBB, 0xBB, 0xBB],
+];
+
+// A screen that lives in ordinary memory, one byte per pixel row by row, so
+// programs can read back what they drew. EG. easy6502 is 32x32 from $0200
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Framebuffer {
+    pub start: u16,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Framebuffer {
+    pub fn new(start: u16, width: usize, height: usize) -> Self {
+        Self { start, width, height }
+    }
+
+    pub fn easy6502() -> Self {
+        Self::new(0x0200, 32, 32)
+    }
+
+    // Four bytes per pixel, read through the CPU so mirrors and devices count
+    pub fn rgba<B: Bus>(&self, cpu: &CPU<B>) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(self.width * self.height * 4);
+        for offset in 0..self.width * self.height {
+            let colour = cpu.effective_read(self.start.wrapping_add(offset as u16)) & 0x0F;
+            pixels.extend_from_slice(&PALETTE[colour as usize]);
+            pixels.push(0xFF);
+        }
+        pixels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_register_follows_the_seed() {
+        let mut expected = XorShift::new(42);
+        let mut cpu = CPU::new();
+        cpu.add_device(RandomRegister::new(0xD000, 42));
+        cpu.load_program(0x0200, &[0xAD, 0x00, 0xD0, 0xAE, 0x00, 0xD0]).unwrap();
+        cpu.set_pc(0x0200);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers().ac, expected.next_u8());
+        assert_eq!(cpu.registers().x, expected.next_u8());
+    }
+
+    #[test]
+    fn framebuffer_maps_colour_bytes_to_rgba() {
+        let mut cpu = CPU::new();
+        cpu.set_framebuffer(Some(Framebuffer::easy6502()));
+        cpu.poke(0x0200, 0x01);
+        // Only the low nibble picks the colour
+        cpu.poke(0x0221, 0x12);
+        let pixels = cpu.framebuffer_rgba();
+        assert_eq!(pixels.len(), 32 * 32 * 4);
+        assert_eq!(&pixels[0..4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+        // Second row, second column
+        assert_eq!(&pixels[33 * 4..34 * 4], &[0x88, 0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn program_reads_the_last_key_pressed() {
+        let mut cpu = CPU::new();
+        cpu.add_device(Easy6502Input::new(1));
+        cpu.load_program(0x0600, &[0xA5, 0xFF]).unwrap();
+        cpu.set_pc(0x0600);
+        cpu.poke(Easy6502Input::KEY, b'w');
+        cpu.step();
+        assert_eq!(cpu.registers().ac, b'w');
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+
+use crate::{bus::{Bus, RamBus}, cpu::{CPU, Variant, le_word}, instructions::Mode, opcodes};
+
+// Turns an opcode and its operand bytes into assembly text, EG. "LDA ($20),Y".
+// The address is where the opcode lives, needed to resolve branch targets.
+// Illegal opcodes get a leading asterisk like nestest's logs, EG. "*LAX $20",
+// and the JAM opcodes, which don't do anything but lock up, come out as data
+pub fn format_instruction(address: u16, opcode: u8, operands: &[u8]) -> String {
+    format_instruction_for(Variant::Nmos, address, opcode, operands)
+}
+
+// Same as format_instruction for the given chip, EG. $B7 is SMB3 on CMOS
+pub fn format_instruction_for(variant: Variant, address: u16, opcode: u8, operands: &[u8]) -> String {
+    let (name, mode) = opcodes::decode_for(opcode, variant);
+    if name == "JAM" {
+        return format!(".byte ${:02X}", opcode);
+    }
+    let mnemonic = if opcodes::is_illegal_for(opcode, variant) {
+        format!("*{}", name)
+    } else {
+        name.to_string()
+    };
+    let byte = operands.first().copied().unwrap_or(0);
+    let word = le_word(byte, operands.get(1).copied().unwrap_or(0));
+    match mode {
+        Mode::A => format!("{} A", mnemonic),
+        Mode::Implied => mnemonic,
+        Mode::Immediate => format!("{} #${:02X}", mnemonic, byte),
+        Mode::Zeropage => format!("{} ${:02X}", mnemonic, byte),
+        Mode::ZeropageX => format!("{} ${:02X},X", mnemonic, byte),
+        Mode::ZeropageY => format!("{} ${:02X},Y", mnemonic, byte),
+        Mode::Absolute => format!("{} ${:04X}", mnemonic, word),
+        Mode::AbsoluteX => format!("{} ${:04X},X", mnemonic, word),
+        Mode::AbsoluteY => format!("{} ${:04X},Y", mnemonic, word),
+        Mode::Indirect => format!("{} (${:04X})", mnemonic, word),
+        Mode::IndirectX => format!("{} (${:02X},X)", mnemonic, byte),
+        Mode::IndirectY => format!("{} (${:02X}),Y", mnemonic, byte),
+        Mode::Relative => {
+            let target = address.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            format!("{} ${:04X}", mnemonic, target)
+        },
+        Mode::ZeropageRelative => {
+            let offset = operands.get(1).copied().unwrap_or(0);
+            let target = address.wrapping_add(3).wrapping_add(offset as i8 as u16);
+            format!("{} ${:02X},${:04X}", mnemonic, byte, target)
+        },
+    }
+}
+
+// Same as format_instruction but any address that has a symbol is shown by
+// name, EG. "JSR init" rather than "JSR $C010"
+pub fn format_instruction_with_symbols(address: u16, opcode: u8, operands: &[u8], symbols: &HashMap<u16, String>) -> String {
+    format_instruction_with_symbols_for(Variant::Nmos, address, opcode, operands, symbols)
+}
+
+pub fn format_instruction_with_symbols_for(variant: Variant, address: u16, opcode: u8, operands: &[u8],
+                                           symbols: &HashMap<u16, String>) -> String {
+    let text = format_instruction_for(variant, address, opcode, operands);
+    let (_, mode) = opcodes::decode_for(opcode, variant);
+    let byte = operands.first().copied().unwrap_or(0);
+    let word = le_word(byte, operands.get(1).copied().unwrap_or(0));
+    let (target, written) = match mode {
+        Mode::Zeropage | Mode::ZeropageX | Mode::ZeropageY |
+        Mode::IndirectX | Mode::IndirectY => (byte as u16, format!("${:02X}", byte)),
+        Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY | Mode::Indirect => (word, format!("${:04X}", word)),
+        Mode::Relative => {
+            let target = address.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            (target, format!("${:04X}", target))
+        },
+        Mode::ZeropageRelative => {
+            let offset = operands.get(1).copied().unwrap_or(0);
+            let target = address.wrapping_add(3).wrapping_add(offset as i8 as u16);
+            (target, format!("${:04X}", target))
+        },
+        Mode::A | Mode::Implied | Mode::Immediate => return text,
+    };
+    match symbols.get(&target) {
+        Some(label) => text.replacen(&written, label, 1),
+        None => text,
+    }
+}
+
+// One instruction's worth of disassembly
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisasmLine {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+// Disassembles a buffer on its own, EG. a .bin file that was never loaded
+// into a CPU. data[0] is taken to live at base so branch targets come out
+// right. An instruction cut short by the end of the data is shown as bytes
+pub fn disassemble_bytes(data: &[u8], base: u16) -> Vec<DisasmLine> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let address = base.wrapping_add(offset as u16);
+        let opcode = data[offset];
+        let (_, mode) = opcodes::decode(opcode);
+        let length = 1 + mode.operand_length() as usize;
+        if offset + length > data.len() {
+            for (i, byte) in data[offset..].iter().enumerate() {
+                lines.push(DisasmLine {
+                    address: address.wrapping_add(i as u16),
+                    bytes: vec![*byte],
+                    text: format!(".byte ${:02X}", byte),
+                });
+            }
+            break;
+        }
+        let bytes = data[offset..offset + length].to_vec();
+        let text = format_instruction(address, opcode, &bytes[1..]);
+        lines.push(DisasmLine { address, bytes, text });
+        offset += length;
+    }
+    lines
+}
+
+// Lazily disassembles memory one instruction at a time, starting at an
+// address and stopping at the top of memory
+pub struct DisasmIter<'a, B: Bus = RamBus> {
+    cpu: &'a CPU<B>,
+    address: Option<u16>,
+}
+
+impl<'a, B: Bus> DisasmIter<'a, B> {
+    pub fn new(cpu: &'a CPU<B>, start: u16) -> Self {
+        Self { cpu, address: Some(start) }
+    }
+}
+
+impl<'a, B: Bus> Iterator for DisasmIter<'a, B> {
+    type Item = (u16, Vec<u8>, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let address = self.address?;
+        let opcode = self.cpu.inspect_memory_at_address(address);
+        let (_, mode) = opcodes::decode_for(opcode, self.cpu.variant);
+        let length = 1 + mode.operand_length();
+        let bytes: Vec<u8> = (0..length)
+            .map(|i| self.cpu.inspect_memory_at_address(address.wrapping_add(i)))
+            .collect();
+        let text = format_instruction_for(self.cpu.variant, address, opcode, &bytes[1..]);
+        self.address = address.checked_add(length);
+        Some((address, bytes, text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iterator_yields_instructions_lazily() {
+        let mut cpu = CPU::new();
+        cpu.load_program(0x0600, &[0xA9, 0x01, 0x8D, 0x00, 0x02, 0xE8, 0xD0, 0xFB, 0x00]).unwrap();
+        let lines: Vec<_> = DisasmIter::new(&cpu, 0x0600).take(5).collect();
+        assert_eq!(lines, vec![
+            (0x0600, vec![0xA9, 0x01], "LDA #$01".to_string()),
+            (0x0602, vec![0x8D, 0x00, 0x02], "STA $0200".to_string()),
+            (0x0605, vec![0xE8], "INX".to_string()),
+            (0x0606, vec![0xD0, 0xFB], "BNE $0603".to_string()),
+            (0x0608, vec![0x00], "BRK".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn branch_targets_and_cut_off_instructions() {
+        let mut cpu = CPU::new();
+        cpu.load_program(0x0600, &[0xD0, 0x02, 0xEA, 0xEA, 0xA9, 0x01]).unwrap();
+        let lines: Vec<_> = cpu.disassemble(0x0600).take(2).map(|(address, _, text)| (address, text)).collect();
+        assert_eq!(lines, vec![(0x0600, "BNE $0604".to_string()), (0x0602, "NOP".to_string())]);
+        // The absolute LDA is missing its high byte
+        let lines = disassemble_bytes(&[0x10, 0xFE, 0xAD, 0x34], 0x0600);
+        let text: Vec<&str> = lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(text, vec!["BPL $0600", ".byte $AD", ".byte $34"]);
+        assert_eq!(lines[2].address, 0x0603);
+    }
+
+    #[test]
+    fn bytes_disassemble_from_their_base() {
+        // LDA #$01, BNE back to it, BEQ forward over a NOP
+        let lines = disassemble_bytes(&[0xA9, 0x01, 0xD0, 0xFC, 0xF0, 0x01, 0xEA], 0xC000);
+        let text: Vec<(u16, &str)> = lines.iter().map(|l| (l.address, l.text.as_str())).collect();
+        assert_eq!(text, vec![
+            (0xC000, "LDA #$01"),
+            (0xC002, "BNE $C000"),
+            (0xC004, "BEQ $C007"),
+            (0xC006, "NOP"),
+        ]);
+        assert_eq!(lines[1].bytes, vec![0xD0, 0xFC]);
+    }
+}
@@ -0,0 +1,35 @@
+use crate::{cpu::{CPU, UnknownOpcode}, rng::XorShift};
+
+// Runs `rounds` random programs against random memory and registers, with
+// unknown opcodes skipped instead of panicking. Passing means nothing in the
+// emulator panicked, the results themselves aren't checked
+pub fn fuzz(seed: u64, rounds: usize, max_instructions: u64) {
+    let mut rng = XorShift::new(seed);
+    for _ in 0..rounds {
+        let mut cpu = CPU::new();
+        cpu.unknown_opcode = UnknownOpcode::TreatAsNop;
+        cpu.illegal_opcodes = true;
+        {
+            let mut memory = cpu.memory.lock().expect("Failed to lock memory");
+            for byte in memory.iter_mut() {
+                *byte = rng.next_u8();
+            }
+        }
+        cpu.set_pc(rng.next_u64() as u16);
+        cpu.set_ac(rng.next_u8());
+        cpu.set_x(rng.next_u8());
+        cpu.set_y(rng.next_u8());
+        cpu.set_sp(rng.next_u8());
+        cpu.run_bounded(max_instructions);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_programs_dont_panic() {
+        fuzz(0x6502, 100, 10_000);
+    }
+}
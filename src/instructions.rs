@@ -1,6 +1,9 @@
-use crate::{CPU, cpu::StatRegister};
+use std::fmt::Display;
+
+use crate::{CPU, bus::{Bus, RamBus}, cpu::{Flag, HaltReason, StatRegister, IRQ_VECTOR, le_word}, opcodes};
 
 // Operates in Little-Endian, lowest byte first then highest byte
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Mode {
     // Operates on the accumulator
     A,
@@ -30,26 +33,160 @@ pub enum Mode {
     ZeropageX,
     // Operates on an address that is only 8 bits, so only first 256 bytes of memory, incremented by Y, note will never access more than #FF so #01FF will ignore the 01
     ZeropageY,
+    // 65C02 bit branches, a zero page address to test then a relative offset
+    ZeropageRelative,
+}
+
+impl Mode {
+    // How many operand bytes follow the opcode
+    pub fn operand_length(&self) -> u16 {
+        match self {
+            Mode::A | Mode::Implied => 0,
+            Mode::Immediate | Mode::IndirectX | Mode::IndirectY | Mode::Relative |
+            Mode::Zeropage | Mode::ZeropageX | Mode::ZeropageY => 1,
+            Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY | Mode::Indirect |
+            Mode::ZeropageRelative => 2,
+        }
+    }
+
+    // Reads the operand bytes after the opcode and works out the effective
+    // address, leaving PC on the last byte of the instruction. Also reports
+    // whether indexing crossed into another page, which costs a cycle on reads
+    pub fn get_address<B: Bus>(&self, cpu: &mut CPU<B>) -> (u16, bool) {
+        match self {
+            Mode::Immediate => {
+                let pc = cpu.registers().pc.wrapping_add(1);
+                cpu.set_pc(pc);
+                (pc, false)
+            },
+            Mode::Zeropage => (cpu.fetch_operand() as u16, false),
+            Mode::ZeropageX => (cpu.fetch_operand().wrapping_add(cpu.registers().x) as u16, false),
+            Mode::ZeropageY => (cpu.fetch_operand().wrapping_add(cpu.registers().y) as u16, false),
+            Mode::Absolute => (cpu.fetch_operand_word(), false),
+            Mode::AbsoluteX => {
+                let base = cpu.fetch_operand_word();
+                let address = base.wrapping_add(cpu.registers().x as u16);
+                (address, base & 0xFF00 != address & 0xFF00)
+            },
+            Mode::AbsoluteY => {
+                let base = cpu.fetch_operand_word();
+                let address = base.wrapping_add(cpu.registers().y as u16);
+                (address, base & 0xFF00 != address & 0xFF00)
+            },
+            // The high byte of the pointer is read without carrying into the next page
+            Mode::Indirect => {
+                let pointer = cpu.fetch_operand_word();
+                let low = cpu.get_memory_at_address(pointer);
+                let high_address = (pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF);
+                let high = cpu.get_memory_at_address(high_address);
+                (le_word(low, high), false)
+            },
+            // The pointer lives in the zero page and wraps around inside it.
+            // X is added before the pointer is read, so there's never a page
+            // to cross and the cost is fixed, unlike (zp),Y below
+            Mode::IndirectX => {
+                let pointer = cpu.fetch_operand().wrapping_add(cpu.registers().x);
+                let low = cpu.get_memory_at_address(pointer as u16);
+                let high = cpu.get_memory_at_address(pointer.wrapping_add(1) as u16);
+                (le_word(low, high), false)
+            },
+            Mode::IndirectY => {
+                let pointer = cpu.fetch_operand();
+                let low = cpu.get_memory_at_address(pointer as u16);
+                let high = cpu.get_memory_at_address(pointer.wrapping_add(1) as u16);
+                let base = le_word(low, high);
+                let address = base.wrapping_add(cpu.registers().y as u16);
+                (address, base & 0xFF00 != address & 0xFF00)
+            },
+            // Just the zero page byte being tested, the branch fetches its
+            // offset afterwards through Relative
+            Mode::ZeropageRelative => (cpu.fetch_operand() as u16, false),
+            // Offset is from the start of the next instruction
+            Mode::Relative => {
+                let offset = cpu.fetch_operand() as i8;
+                let next = cpu.registers().pc.wrapping_add(1);
+                let target = next.wrapping_add(offset as u16);
+                (target, next & 0xFF00 != target & 0xFF00)
+            },
+            // Accumulator instructions go through get_memory/set_memory instead
+            Mode::A => {
+                debug_assert!(false, "the accumulator mode has no address");
+                (0, false)
+            },
+            // Nothing to address, an implied instruction shouldn't end up here
+            Mode::Implied => {
+                debug_assert!(false, "the implied mode has no address");
+                (0, false)
+            },
+        }
+    }
+
+    // Fetches the operand, for the accumulator mode that's the accumulator
+    // itself. Hands back the address as well so read-modify-write
+    // instructions can store the result without reading the operands again
+    pub fn get_memory<B: Bus>(&self, cpu: &mut CPU<B>) -> (u16, u8, bool) {
+        match self {
+            Mode::A => (0, cpu.registers().ac, false),
+            _ => {
+                let (address, page_crossed) = self.get_address(cpu);
+                (address, cpu.get_memory_at_address(address), page_crossed)
+            }
+        }
+    }
+
+    // Only stores the value, instructions that affect flags set them themselves
+    // since stores like STA don't touch any. The accumulator goes through
+    // set_ac, which sets N and Z from the same value the shifts then set them from
+    pub fn set_memory<B: Bus>(&self, cpu: &mut CPU<B>, address: u16, value: u8) {
+        match self {
+            Mode::A => cpu.set_ac(value),
+            _ => cpu.set_memory_at_address(address, value),
+        }
+    }
 }
 
-pub trait Instruction {
-    fn get_opcodes(&self) -> Vec<i16>;
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool;
+// Standard notation for the mode, EG. "Absolute,X" or "(Indirect),Y"
+impl Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Mode::A => "Accumulator",
+            Mode::Absolute => "Absolute",
+            Mode::AbsoluteX => "Absolute,X",
+            Mode::AbsoluteY => "Absolute,Y",
+            Mode::Immediate => "Immediate",
+            Mode::Implied => "Implied",
+            Mode::Indirect => "(Indirect)",
+            Mode::IndirectX => "(Indirect,X)",
+            Mode::IndirectY => "(Indirect),Y",
+            Mode::Relative => "Relative",
+            Mode::Zeropage => "Zeropage",
+            Mode::ZeropageX => "Zeropage,X",
+            Mode::ZeropageY => "Zeropage,Y",
+            Mode::ZeropageRelative => "Zeropage,Relative",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub trait Instruction<B: Bus = RamBus>: Send + Sync {
+    fn get_opcodes(&self) -> Vec<u8>;
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool;
 }
 
 #[macro_export]
 macro_rules! instruction {
     ( $name:ident, $opcodes:expr, $execute:item) => {
-        #[allow(dead_code)]
+        #[allow(dead_code, clippy::upper_case_acronyms)]
         pub struct $name {
-            opcodes: Vec<i16>,
+            opcodes: Vec<u8>,
         }
 
-        impl Instruction for $name {
-            fn get_opcodes(&self) -> Vec<i16> {
+        impl<B: Bus> Instruction<B> for $name {
+            fn get_opcodes(&self) -> Vec<u8> {
                 self.opcodes.clone()
             }
 
+            #[allow(unused_variables)]
             $execute
         }
 
@@ -60,297 +197,1227 @@ macro_rules! instruction {
             }
 
             #[allow(dead_code)]
-            pub fn get_opcode(&self, index: usize) -> i16 {
+            pub fn get_opcode(&self, index: usize) -> u8 {
                 self.opcodes[index]
             }
         }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
     };
 }
 
 
-pub fn init_instructions() -> Vec<Box<dyn Instruction>> {
-    let mut instructions: Vec<Box<dyn Instruction>> = Vec::new();
-    instructions.push(Box::new(BRK::new()));
-    instructions.push(Box::new(BPL::new()));
-    instructions.push(Box::new(JSR::new()));
-    instructions.push(Box::new(BMI::new()));
-    instructions.push(Box::new(RTI::new()));
-    instructions.push(Box::new(BVC::new()));
-    instructions.push(Box::new(RTS::new()));
-    instructions.push(Box::new(BVS::new()));
-    instructions.push(Box::new(BCC::new()));
-    instructions.push(Box::new(LDY::new()));
-    instructions.push(Box::new(BCS::new()));
-    instructions.push(Box::new(CPY::new()));
-    instructions.push(Box::new(BNE::new()));
-    instructions.push(Box::new(CPX::new()));
-    instructions.push(Box::new(BEQ::new()));
-    instructions.push(Box::new(ORA::new()));
-    instructions.push(Box::new(AND::new()));
-    instructions.push(Box::new(EOR::new()));
-    instructions.push(Box::new(ADC::new()));
-    instructions.push(Box::new(STA::new()));
-    instructions.push(Box::new(LDA::new()));
-    instructions.push(Box::new(CMP::new()));
-    instructions.push(Box::new(SBC::new()));
-    instructions.push(Box::new(LDX::new()));
-    instructions.push(Box::new(BIT::new()));
-    instructions.push(Box::new(STY::new()));
-    instructions.push(Box::new(ASL::new()));
-    instructions.push(Box::new(ROL::new()));
-    instructions.push(Box::new(LSR::new()));
-    instructions.push(Box::new(ROR::new()));
-    instructions.push(Box::new(STX::new()));
-    instructions.push(Box::new(DEC::new()));
-    instructions.push(Box::new(INC::new()));
-    instructions.push(Box::new(NOP::new()));
+// Indexed reads that cross a page boundary take a cycle to fix up the high
+// byte of the address, writes always spend it so their base cost covers it
+fn page_penalty<B: Bus>(opcode: u8, cpu: &mut CPU<B>, page_crossed: bool) {
+    if page_crossed && opcodes::access(opcode) == opcodes::Access::Read {
+        cpu.extra_cycles += 1;
+    }
+}
+
+// Shared by all the branch instructions. A branch that isn't taken costs just
+// the base 2 cycles and carries on after the offset byte, a taken one costs a
+// cycle more and another one if it lands in a different page
+fn branch<B: Bus>(opcode: u8, cpu: &mut CPU<B>) -> bool {
+    let (target, page_crossed) = Mode::Relative.get_address(cpu);
+    if cpu.branch_taken(opcode) {
+        cpu.extra_cycles += 1 + page_crossed as u8;
+        cpu.set_pc(target);
+        false
+    } else {
+        true
+    }
+}
+
+// BBR and BBS, the bit number is the high nibble of the opcode with the top
+// bit dropped. A zero page address then a relative offset follow the opcode
+fn branch_on_bit<B: Bus>(opcode: u8, cpu: &mut CPU<B>, set: bool) -> bool {
+    let bit = (opcode >> 4) & 0x07;
+    let (_, value, _) = Mode::ZeropageRelative.get_memory(cpu);
+    let (target, page_crossed) = Mode::Relative.get_address(cpu);
+    if (value & (1 << bit) != 0) == set {
+        cpu.extra_cycles += 1 + page_crossed as u8;
+        cpu.set_pc(target);
+        false
+    } else {
+        true
+    }
+}
+
+// The shifts and rotates, shared with the illegal opcodes built on them.
+// Each moves the bit that falls off into carry and hands back the result,
+// the caller decides where it goes and which flags it sets from it
+fn shift_left<B: Bus>(cpu: &mut CPU<B>, value: u8) -> u8 {
+    cpu.set_flag(Flag::Carry, value & 0x80 != 0);
+    value << 1
+}
+
+fn rotate_left<B: Bus>(cpu: &mut CPU<B>, value: u8) -> u8 {
+    let carry = cpu.registers().sr.carry as u8;
+    cpu.set_flag(Flag::Carry, value & 0x80 != 0);
+    (value << 1) | carry
+}
+
+fn shift_right<B: Bus>(cpu: &mut CPU<B>, value: u8) -> u8 {
+    cpu.set_flag(Flag::Carry, value & 0x01 != 0);
+    value >> 1
+}
+
+fn rotate_right<B: Bus>(cpu: &mut CPU<B>, value: u8) -> u8 {
+    let carry = cpu.registers().sr.carry as u8;
+    cpu.set_flag(Flag::Carry, value & 0x01 != 0);
+    (value >> 1) | (carry << 7)
+}
+
+// INC and DEC's read-modify-write, shared with ISC and DCP. The byte at the
+// operand's address goes up or down by one, wrapping, and the result is
+// handed back. N and Z are left to the caller since the illegal ones set
+// them from the ALU op that follows instead
+fn step_memory<B: Bus>(opcode: u8, cpu: &mut CPU<B>, up: bool) -> u8 {
+    let (_, mode) = opcodes::decode(opcode);
+    let (address, value, _) = mode.get_memory(cpu);
+    let result = if up { value.wrapping_add(1) } else { value.wrapping_sub(1) };
+    mode.set_memory(cpu, address, result);
+    result
+}
+
+// CMP, CPX and CPY, a subtraction that only keeps the flags. Carry set means
+// the register was at least the value, decimal mode doesn't come into it
+pub fn compare<B: Bus>(cpu: &mut CPU<B>, register: u8, value: u8) {
+    cpu.set_flag(Flag::Carry, register >= value);
+    cpu.update_zero_negative(register.wrapping_sub(value));
+}
+
+// ADC, shared with the illegal opcodes that add. In decimal mode the NMOS chip
+// still sets Z from the binary sum, and N and V from the sum before the high
+// digit gets corrected, only carry and the stored result are BCD
+pub fn add_with_carry<B: Bus>(cpu: &mut CPU<B>, value: u8) {
+    let ac = cpu.registers().ac;
+    let carry = cpu.registers().sr.carry as u16;
+    let binary = ac as u16 + value as u16 + carry;
+    if cpu.registers().sr.decimal {
+        let mut low = (ac & 0x0F) as u16 + (value & 0x0F) as u16 + carry;
+        if low >= 0x0A {
+            low = ((low + 0x06) & 0x0F) + 0x10;
+        }
+        let mut sum = (ac & 0xF0) as u16 + (value & 0xF0) as u16 + low;
+        let negative = sum & 0x80 != 0;
+        let overflow = (ac as u16 ^ sum) & (value as u16 ^ sum) & 0x80 != 0;
+        if sum >= 0xA0 {
+            sum += 0x60;
+        }
+        // set_ac takes N from the corrected result, so it's put back after
+        cpu.set_ac(sum as u8);
+        cpu.set_flag(Flag::Negative, negative);
+        cpu.set_flag(Flag::Overflow, overflow);
+        cpu.set_flag(Flag::Carry, sum >= 0x100);
+    } else {
+        let result = binary as u8;
+        cpu.set_ac(result);
+        cpu.set_flag(Flag::Overflow, (ac ^ result) & (value ^ result) & 0x80 != 0);
+        cpu.set_flag(Flag::Carry, binary > 0xFF);
+    }
+    cpu.set_flag(Flag::Zero, binary & 0xFF == 0);
+}
+
+// SBC, carry set means no borrow. On NMOS every flag comes from the binary
+// subtraction even in decimal mode, only the stored result is BCD
+pub fn subtract_with_borrow<B: Bus>(cpu: &mut CPU<B>, value: u8) {
+    let ac = cpu.registers().ac;
+    let borrow = 1 - cpu.registers().sr.carry as i16;
+    let binary = ac as i16 - value as i16 - borrow;
+    let result = binary as u8;
+    cpu.set_flag(Flag::Carry, binary >= 0);
+    cpu.set_flag(Flag::Overflow, (ac ^ value) & (ac ^ result) & 0x80 != 0);
+    if cpu.registers().sr.decimal {
+        let mut low = (ac & 0x0F) as i16 - (value & 0x0F) as i16 - borrow;
+        if low < 0 {
+            low = ((low - 0x06) & 0x0F) - 0x10;
+        }
+        let mut difference = (ac & 0xF0) as i16 - (value & 0xF0) as i16 + low;
+        if difference < 0 {
+            difference -= 0x60;
+        }
+        cpu.set_ac(difference as u8);
+    } else {
+        cpu.set_ac(result);
+    }
+    // After set_ac, which would take them from the BCD result
+    cpu.update_zero_negative(result);
+}
+
+pub fn init_instructions<B: Bus>() -> Vec<Box<dyn Instruction<B>>> {
+    let instructions: Vec<Box<dyn Instruction<B>>> = vec![
+        Box::new(BRK::new()),
+        Box::new(BPL::new()),
+        Box::new(JSR::new()),
+        Box::new(JMP::new()),
+        Box::new(BMI::new()),
+        Box::new(RTI::new()),
+        Box::new(BVC::new()),
+        Box::new(RTS::new()),
+        Box::new(BVS::new()),
+        Box::new(BCC::new()),
+        Box::new(LDY::new()),
+        Box::new(BCS::new()),
+        Box::new(CPY::new()),
+        Box::new(BNE::new()),
+        Box::new(CPX::new()),
+        Box::new(BEQ::new()),
+        Box::new(ORA::new()),
+        Box::new(AND::new()),
+        Box::new(EOR::new()),
+        Box::new(ADC::new()),
+        Box::new(STA::new()),
+        Box::new(LDA::new()),
+        Box::new(CMP::new()),
+        Box::new(SBC::new()),
+        Box::new(LDX::new()),
+        Box::new(BIT::new()),
+        Box::new(STY::new()),
+        Box::new(ASL::new()),
+        Box::new(ROL::new()),
+        Box::new(LSR::new()),
+        Box::new(ROR::new()),
+        Box::new(STX::new()),
+        Box::new(DEC::new()),
+        Box::new(INC::new()),
+        Box::new(NOP::new()),
+        Box::new(PHP::new()),
+        Box::new(PLP::new()),
+        Box::new(PHA::new()),
+        Box::new(PLA::new()),
+        Box::new(CLC::new()),
+        Box::new(SEC::new()),
+        Box::new(CLI::new()),
+        Box::new(SEI::new()),
+        Box::new(CLV::new()),
+        Box::new(CLD::new()),
+        Box::new(SED::new()),
+        Box::new(ANC::new()),
+        Box::new(ALR::new()),
+        Box::new(ARR::new()),
+        Box::new(AXS::new()),
+        Box::new(SLO::new()),
+        Box::new(RLA::new()),
+        Box::new(SRE::new()),
+        Box::new(RRA::new()),
+        Box::new(DCP::new()),
+        Box::new(ISC::new()),
+        Box::new(JAM::new()),
+    ];
+    instructions
+}
 
+// The 65C02's additions, dispatched ahead of the NMOS set on a CMOS CPU since
+// a lot of them reuse opcodes that are illegal on NMOS
+pub fn init_cmos_instructions<B: Bus>() -> Vec<Box<dyn Instruction<B>>> {
+    let instructions: Vec<Box<dyn Instruction<B>>> = vec![
+        Box::new(RMB::new()),
+        Box::new(SMB::new()),
+        Box::new(BBR::new()),
+        Box::new(BBS::new()),
+    ];
     instructions
 }
 
+// Which instruction in the set handles each opcode byte. Two instructions
+// claiming the same opcode is a mistake in the set, so rather than one of
+// them quietly winning depending on the order it refuses to build the table
+pub fn dispatch_table<B: Bus>(instructions: &[Box<dyn Instruction<B>>]) -> [Option<usize>; 256] {
+    let mut table = [None; 256];
+    for (index, instruction) in instructions.iter().enumerate() {
+        for opcode in instruction.get_opcodes() {
+            let entry = &mut table[opcode as usize];
+            if entry.is_some() {
+                panic!("Opcode {:02X} is registered by more than one instruction", opcode);
+            }
+            *entry = Some(index);
+        }
+    }
+    table
+}
+
 instruction!(BRK, vec![0x00],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        cpu.push_to_stack((cpu.registers.pc + 2 >> 8) as u8);
-        cpu.push_to_stack((cpu.registers.pc + 2) as u8);
-        cpu.push_to_stack(u8::from(cpu.registers.sr));
-        let interrupt: u8 = u8::from(cpu.registers.sr) & 0b100;
-        cpu.registers.sr = StatRegister::from(interrupt);
-        let memory_lock = cpu.memory.clone();
-        let memory = memory_lock.lock().unwrap();
-        cpu.registers.pc = (memory[(cpu.registers.pc + 1) as usize] as i16) as u16;
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // BRK skips over its padding byte, so the return address is PC + 2
+        cpu.set_pc(cpu.registers().pc.wrapping_add(2));
+        cpu.interrupt(IRQ_VECTOR, true);
         false
     }
 );
 instruction!(BPL, vec![0x10],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        cpu.registers.increment_pc();
-        if !cpu.registers.sr.negative {
-            let address = cpu.registers.increment_pc();
-            cpu.registers.pc = (cpu.registers.pc as i16).wrapping_add(cpu.get_memory_at_address(address)) as u16;
-            false
-        } else {
-            true
-        }
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        branch(*opcode, cpu)
     }
 );
 instruction!(JSR, vec![0x20],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        cpu.push_to_stack((cpu.registers.pc + 2 >> 8) as u8);
-        cpu.push_to_stack((cpu.registers.pc + 2) as u8);
-        cpu.registers.pc = (cpu.registers.pc as i16 + cpu.get_memory_at_address(cpu.registers.pc + 1) as i16) as u16;
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // Follows the hardware's six cycles: opcode, low byte of the target,
+        // an internal cycle on the stack, push PCH, push PCL and only then the
+        // high byte of the target. The return address pushed is the last byte
+        // of the JSR, RTS adds the one. Reading the high byte last matters if
+        // the pushes overwrite it, EG. code running out of the stack page
+        let low = cpu.fetch_operand();
+        let pc = cpu.registers().pc.wrapping_add(1);
+        cpu.push_to_stack((pc >> 8) as u8);
+        cpu.push_to_stack(pc as u8);
+        let high = cpu.fetch_operand();
+        cpu.set_pc(le_word(low, high));
+        false
+    }
+);
+instruction!(JMP, vec![0x4C, 0x6C],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // Indirect goes through the mode, which has the NMOS bug where
+        // JMP ($xxFF) takes the high byte from $xx00 instead of the next page
+        let (_, mode) = opcodes::decode(*opcode);
+        let (target, _) = mode.get_address(cpu);
+        cpu.set_pc(target);
         false
     }
 );
 instruction!(BMI, vec![0x30],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        if cpu.registers.sr.negative {
-            cpu.registers.pc = (cpu.registers.pc as i16 + cpu.get_memory_at_address(cpu.registers.pc + 1) as i16) as u16;
-            false
-        } else {
-            true
-        }
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        branch(*opcode, cpu)
     }
 );
 instruction!(RTI, vec![0x40],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        cpu.registers.sr = StatRegister::from(cpu.pull_from_stack());
-        cpu.registers.pc = cpu.pull_from_stack() as u16 | ((cpu.pull_from_stack() as u16) << 8);
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // Same as PLP, the B pushed by a BRK doesn't end up in the register
+        let mut status = StatRegister::from(cpu.pull_from_stack());
+        status.sbreak = cpu.registers().sr.sbreak;
+        status.ignored = true;
+        cpu.set_status(status);
+        let low = cpu.pull_from_stack();
+        let high = cpu.pull_from_stack();
+        cpu.set_pc(le_word(low, high));
         false
     }
 );
 instruction!(BVC, vec![0x50],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        if !cpu.registers.sr.overflow {
-            cpu.registers.pc = (cpu.registers.pc as i16).wrapping_add(cpu.get_memory_at_address(cpu.registers.pc + 1) as i16) as u16;
-            false
-        } else {
-            true
-        }
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        branch(*opcode, cpu)
     }
 );
 instruction!(RTS, vec![0x60],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        cpu.registers.pc = cpu.pull_from_stack() as u16 | ((cpu.pull_from_stack() as u16) << 8);
-        false
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let low = cpu.pull_from_stack();
+        let high = cpu.pull_from_stack();
+        cpu.set_pc(le_word(low, high));
+        true
     }
 );
 instruction!(BVS, vec![0x70],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        if cpu.registers.sr.overflow {
-            cpu.registers.pc = (cpu.registers.pc as i16).wrapping_add(cpu.get_memory_at_address(cpu.registers.pc + 1) as i16) as u16;
-            false
-        } else {
-            true
-        }
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        branch(*opcode, cpu)
     }
 );
 instruction!(BCC, vec![0x90],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        if !cpu.registers.sr.carry {
-            cpu.registers.pc = (cpu.registers.pc as i16).wrapping_add(cpu.get_memory_at_address(cpu.registers.pc + 1) as i16) as u16;
-            false
-        } else {
-            true
-        }
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        branch(*opcode, cpu)
     }
 );
 instruction!(LDY, vec![0xA0, 0xA4, 0xB4, 0xAC, 0xBC],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        cpu.registers.increment_pc();
-        match opcode {
-            0xA0 => {
-                let address = cpu.registers.increment_pc();
-                cpu.registers.y = cpu.get_memory_at_address(address) as u8;
-            },
-            0xA4 => {
-                let address = cpu.registers.increment_pc();
-                cpu.registers.y = cpu.get_memory_at_address(address & 0xFF) as u8;
-            },
-            0xB4 => {
-                let address = cpu.registers.increment_pc();
-                let x_register = cpu.registers.x;
-                cpu.registers.y = cpu.get_memory_at_address(((address & 0xFF) as u8).wrapping_add(x_register) as u16) as u8;
-            },
-            0xAC => {
-                let first_half = cpu.registers.increment_pc();
-                let second_half = cpu.registers.increment_pc();
-                cpu.registers.y = cpu.get_memory_at_address(first_half | (second_half << 8)) as u8;
-            },
-            0xBC => {
-                let first_half = cpu.registers.increment_pc();
-                let second_half = cpu.registers.increment_pc();
-                let x_register = cpu.registers.x;
-                cpu.registers.y = cpu.get_memory_at_address((first_half | (second_half << 8)).wrapping_add(x_register as u16) as u16) as u8;
-            },
-            _ => {}
-        }
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (_, value, page_crossed) = mode.get_memory(cpu);
+        page_penalty(*opcode, cpu, page_crossed);
+        cpu.set_y(value);
         true
     }
 );
 instruction!(BCS, vec![0xB0],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        true
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        branch(*opcode, cpu)
     }
 );
-instruction!(CPY, vec![0xC0, 0xC4],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(CPY, vec![0xC0, 0xC4, 0xCC],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (_, value, _) = mode.get_memory(cpu);
+        compare(cpu, cpu.registers().y, value);
         true
     }
 );
 instruction!(BNE, vec![0xD0],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        true
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        branch(*opcode, cpu)
     }
 );
-instruction!(CPX, vec![0xE0, 0xE4],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(CPX, vec![0xE0, 0xE4, 0xEC],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (_, value, _) = mode.get_memory(cpu);
+        compare(cpu, cpu.registers().x, value);
         true
     }
 );
 instruction!(BEQ, vec![0xF0],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        branch(*opcode, cpu)
+    }
+);
+instruction!(ORA, vec![0x09, 0x05, 0x15, 0x0D, 0x1D, 0x19, 0x01, 0x11],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (_, value, page_crossed) = mode.get_memory(cpu);
+        page_penalty(*opcode, cpu, page_crossed);
+        cpu.set_ac(cpu.registers().ac | value);
+        true
+    }
+);
+instruction!(AND, vec![0x29, 0x25, 0x35, 0x2D, 0x3D, 0x39, 0x21, 0x31],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (_, value, page_crossed) = mode.get_memory(cpu);
+        page_penalty(*opcode, cpu, page_crossed);
+        cpu.set_ac(cpu.registers().ac & value);
+        true
+    }
+);
+instruction!(EOR, vec![0x49, 0x45, 0x55, 0x4D, 0x5D, 0x59, 0x41, 0x51],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (_, value, page_crossed) = mode.get_memory(cpu);
+        page_penalty(*opcode, cpu, page_crossed);
+        cpu.set_ac(cpu.registers().ac ^ value);
+        true
+    }
+);
+instruction!(ADC, vec![0x69, 0x65, 0x75, 0x6D, 0x7D, 0x79, 0x61, 0x71],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (_, value, page_crossed) = mode.get_memory(cpu);
+        page_penalty(*opcode, cpu, page_crossed);
+        add_with_carry(cpu, value);
+        true
+    }
+);
+instruction!(STA, vec![0x85, 0x95, 0x8D, 0x9D, 0x99, 0x81, 0x91],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // Stores don't affect any flags
+        let (_, mode) = opcodes::decode(*opcode);
+        let (address, _) = mode.get_address(cpu);
+        mode.set_memory(cpu, address, cpu.registers().ac);
+        true
+    }
+);
+instruction!(LDA, vec![0xA9, 0xA5, 0xB5, 0xAD, 0xBD, 0xB9, 0xA1, 0xB1],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (_, value, page_crossed) = mode.get_memory(cpu);
+        page_penalty(*opcode, cpu, page_crossed);
+        cpu.set_ac(value);
+        true
+    }
+);
+instruction!(CMP, vec![0xC9, 0xC5, 0xD5, 0xCD, 0xDD, 0xD9, 0xC1, 0xD1],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (_, value, page_crossed) = mode.get_memory(cpu);
+        page_penalty(*opcode, cpu, page_crossed);
+        compare(cpu, cpu.registers().ac, value);
+        true
+    }
+);
+instruction!(SBC, vec![0xE9, 0xE5, 0xF5, 0xED, 0xFD, 0xF9, 0xE1, 0xF1],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (_, value, page_crossed) = mode.get_memory(cpu);
+        page_penalty(*opcode, cpu, page_crossed);
+        subtract_with_borrow(cpu, value);
+        true
+    }
+);
+instruction!(LDX, vec![0xA2, 0xA6, 0xB6, 0xAE, 0xBE],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (_, value, page_crossed) = mode.get_memory(cpu);
+        page_penalty(*opcode, cpu, page_crossed);
+        cpu.set_x(value);
+        true
+    }
+);
+instruction!(BIT, vec![0x24, 0x2C],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // Only Z depends on A, N and V are copied straight from bits 7 and 6
+        // of the operand even when the AND comes out as zero
+        let (_, mode) = opcodes::decode(*opcode);
+        let (_, value, _) = mode.get_memory(cpu);
+        cpu.set_flag(Flag::Zero, cpu.registers().ac & value == 0);
+        cpu.set_flag(Flag::Negative, value & 0x80 != 0);
+        cpu.set_flag(Flag::Overflow, value & 0x40 != 0);
+        true
+    }
+);
+instruction!(STY, vec![0x84, 0x94, 0x8C],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // Stores don't affect any flags
+        let (_, mode) = opcodes::decode(*opcode);
+        let (address, _) = mode.get_address(cpu);
+        mode.set_memory(cpu, address, cpu.registers().y);
+        true
+    }
+);
+instruction!(ASL, vec![0x0A, 0x06, 0x16, 0x0E, 0x1E],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (address, value, _) = mode.get_memory(cpu);
+        let result = shift_left(cpu, value);
+        mode.set_memory(cpu, address, result);
+        cpu.update_zero_negative(result);
+        true
+    }
+);
+instruction!(ROL, vec![0x2A, 0x26, 0x36, 0x2E, 0x3E],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (address, value, _) = mode.get_memory(cpu);
+        let result = rotate_left(cpu, value);
+        mode.set_memory(cpu, address, result);
+        cpu.update_zero_negative(result);
         true
     }
 );
-instruction!(ORA, vec![0x01, 0x11, 0x05, 0x15],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(LSR, vec![0x4A, 0x46, 0x56, 0x4E, 0x5E],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (address, value, _) = mode.get_memory(cpu);
+        let result = shift_right(cpu, value);
+        mode.set_memory(cpu, address, result);
+        cpu.update_zero_negative(result);
         true
     }
 );
-instruction!(AND, vec![0x21, 0x31, 0x25, 0x35],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(ROR, vec![0x6A, 0x66, 0x76, 0x6E, 0x7E],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let (_, mode) = opcodes::decode(*opcode);
+        let (address, value, _) = mode.get_memory(cpu);
+        let result = rotate_right(cpu, value);
+        mode.set_memory(cpu, address, result);
+        cpu.update_zero_negative(result);
         true
     }
 );
-instruction!(EOR, vec![0x41, 0x51, 0x45, 0x55],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(STX, vec![0x86, 0x96, 0x8E],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // Stores don't affect any flags
+        let (_, mode) = opcodes::decode(*opcode);
+        let (address, _) = mode.get_address(cpu);
+        mode.set_memory(cpu, address, cpu.registers().x);
         true
     }
 );
-instruction!(ADC, vec![0x61, 0x71, 0x65, 0x75],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(DEC, vec![0xC6, 0xD6, 0xCE, 0xDE],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let result = step_memory(*opcode, cpu, false);
+        cpu.update_zero_negative(result);
         true
     }
 );
-instruction!(STA, vec![0x81, 0x91, 0x85, 0x95],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(INC, vec![0xE6, 0xF6, 0xEE, 0xFE],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let result = step_memory(*opcode, cpu, true);
+        cpu.update_zero_negative(result);
         true
     }
 );
-instruction!(LDA, vec![0xA1, 0xB1, 0xA5, 0xB5],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+
+instruction!(NOP, vec![0xEA],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        true
+    }
+);
+instruction!(PHP, vec![0x08],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // The pushed copy always has the break and unused bits set
+        let mut status = cpu.registers().sr;
+        status.sbreak = true;
+        status.ignored = true;
+        cpu.push_to_stack(u8::from(status));
+        true
+    }
+);
+instruction!(PLP, vec![0x28],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // Break isn't a real bit in the register so it's left as it was,
+        // and the unused bit always reads back as set
+        let mut status = StatRegister::from(cpu.pull_from_stack());
+        status.sbreak = cpu.registers().sr.sbreak;
+        status.ignored = true;
+        cpu.set_status(status);
+        true
+    }
+);
+instruction!(PHA, vec![0x48],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        cpu.push_to_stack(cpu.registers().ac);
+        true
+    }
+);
+instruction!(PLA, vec![0x68],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let value = cpu.pull_from_stack();
+        cpu.set_ac(value);
+        true
+    }
+);
+
+instruction!(CLC, vec![0x18],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        cpu.set_flag(Flag::Carry, false);
         true
     }
 );
-instruction!(CMP, vec![0xC1, 0xD1, 0xC5, 0xD5],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(SEC, vec![0x38],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        cpu.set_flag(Flag::Carry, true);
         true
     }
 );
-instruction!(SBC, vec![0xE1, 0xF1, 0xE5, 0xF5],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(CLI, vec![0x58],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // Pending IRQs still wait until after the next instruction, see step
+        cpu.set_flag(Flag::Interrupt, false);
         true
     }
 );
-instruction!(LDX, vec![0xA2, 0xA6, 0xB6],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        match opcode {
-            0xA2 => {
-                cpu.registers.x = cpu.get_memory_at_address(cpu.registers.pc + 1) as u8;
-                true
+instruction!(SEI, vec![0x78],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        cpu.set_flag(Flag::Interrupt, true);
+        true
+    }
+);
+instruction!(CLV, vec![0xB8],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        cpu.set_flag(Flag::Overflow, false);
+        true
+    }
+);
+instruction!(CLD, vec![0xD8],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        cpu.set_flag(Flag::Decimal, false);
+        true
+    }
+);
+instruction!(SED, vec![0xF8],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        cpu.set_flag(Flag::Decimal, true);
+        true
+    }
+);
+
+// The undocumented opcodes below only run when the CPU has illegal_opcodes on
+
+instruction!(ANC, vec![0x0B, 0x2B],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // AND, then bit 7 of the result is copied into carry as well as N
+        let (_, value, _) = Mode::Immediate.get_memory(cpu);
+        cpu.set_ac(cpu.registers().ac & value);
+        cpu.set_flag(Flag::Carry, cpu.registers().sr.negative);
+        true
+    }
+);
+instruction!(ALR, vec![0x4B],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // AND followed by LSR A
+        let (_, value, _) = Mode::Immediate.get_memory(cpu);
+        let and = cpu.registers().ac & value;
+        cpu.set_flag(Flag::Carry, and & 0x01 != 0);
+        cpu.set_ac(and >> 1);
+        true
+    }
+);
+instruction!(ARR, vec![0x6B],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // AND followed by ROR A, except C and V come from bits 6 and 5 of the
+        // rotated result as if it had gone through the adder
+        let (_, value, _) = Mode::Immediate.get_memory(cpu);
+        let and = cpu.registers().ac & value;
+        let carry = cpu.registers().sr.carry as u8;
+        let rotated = (and >> 1) | (carry << 7);
+        let mut result = rotated;
+        if cpu.registers().sr.decimal {
+            // N still comes from the old carry and V from the unrotated bits,
+            // then each digit gets a BCD fixup like ADC would do
+            cpu.set_flag(Flag::Overflow, (and ^ result) & 0x40 != 0);
+            if (and & 0x0F) + (and & 0x01) > 0x05 {
+                result = (result & 0xF0) | (result.wrapping_add(0x06) & 0x0F);
+            }
+            let high_fixup = (and as u16 + (and & 0x10) as u16) & 0x1F0 > 0x50;
+            cpu.set_flag(Flag::Carry, high_fixup);
+            if high_fixup {
+                result = result.wrapping_add(0x60);
             }
-            _ => false
+        } else {
+            cpu.set_flag(Flag::Carry, result & 0x40 != 0);
+            cpu.set_flag(Flag::Overflow, ((result >> 6) ^ (result >> 5)) & 0x01 != 0);
         }
+        cpu.set_ac(result);
+        // N and Z are from before any BCD fixup
+        cpu.update_zero_negative(rotated);
+        true
     }
 );
-instruction!(BIT, vec![0x24],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(AXS, vec![0xCB],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // X = (A & X) - operand, a compare really so there's no borrow in,
+        // V is left alone and decimal mode doesn't apply
+        let (_, value, _) = Mode::Immediate.get_memory(cpu);
+        let and = cpu.registers().ac & cpu.registers().x;
+        cpu.set_flag(Flag::Carry, and >= value);
+        cpu.set_x(and.wrapping_sub(value));
         true
     }
 );
-instruction!(STY, vec![0x84, 0x94],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+
+// The read-modify-write illegals, each does the memory half exactly like the
+// documented instruction and then feeds the result to an ALU op on A
+
+instruction!(SLO, vec![0x07, 0x17, 0x0F, 0x1F, 0x1B, 0x03, 0x13],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // ASL then ORA
+        let (_, mode) = opcodes::decode(*opcode);
+        let (address, value, _) = mode.get_memory(cpu);
+        let result = shift_left(cpu, value);
+        mode.set_memory(cpu, address, result);
+        cpu.set_ac(cpu.registers().ac | result);
         true
     }
 );
-instruction!(ASL, vec![0x06, 0x16],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(RLA, vec![0x27, 0x37, 0x2F, 0x3F, 0x3B, 0x23, 0x33],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // ROL then AND
+        let (_, mode) = opcodes::decode(*opcode);
+        let (address, value, _) = mode.get_memory(cpu);
+        let result = rotate_left(cpu, value);
+        mode.set_memory(cpu, address, result);
+        cpu.set_ac(cpu.registers().ac & result);
         true
     }
 );
-instruction!(ROL, vec![0x26, 0x36],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(SRE, vec![0x47, 0x57, 0x4F, 0x5F, 0x5B, 0x43, 0x53],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // LSR then EOR
+        let (_, mode) = opcodes::decode(*opcode);
+        let (address, value, _) = mode.get_memory(cpu);
+        let result = shift_right(cpu, value);
+        mode.set_memory(cpu, address, result);
+        cpu.set_ac(cpu.registers().ac ^ result);
         true
     }
 );
-instruction!(LSR, vec![0x46, 0x56],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(RRA, vec![0x67, 0x77, 0x6F, 0x7F, 0x7B, 0x63, 0x73],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // ROR then ADC, which adds in the carry the rotate just shifted out
+        let (_, mode) = opcodes::decode(*opcode);
+        let (address, value, _) = mode.get_memory(cpu);
+        let result = rotate_right(cpu, value);
+        mode.set_memory(cpu, address, result);
+        add_with_carry(cpu, result);
         true
     }
 );
-instruction!(ROR, vec![0x66, 0x76],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(DCP, vec![0xC7, 0xD7, 0xCF, 0xDF, 0xDB, 0xC3, 0xD3],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // DEC then CMP
+        let result = step_memory(*opcode, cpu, false);
+        compare(cpu, cpu.registers().ac, result);
         true
     }
 );
-instruction!(STX, vec![0x86, 0x96],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(ISC, vec![0xE7, 0xF7, 0xEF, 0xFF, 0xFB, 0xE3, 0xF3],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // INC then SBC
+        let result = step_memory(*opcode, cpu, true);
+        subtract_with_borrow(cpu, result);
         true
     }
 );
-instruction!(DEC, vec![0xC6, 0xD6],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+
+instruction!(JAM, vec![0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        // Locks the chip up with PC stuck on the opcode, only a reset gets out
+        cpu.halt(HaltReason::Jammed(cpu.registers().pc));
+        false
+    }
+);
+
+// 65C02 bit instructions, only run on the CMOS variant. RMB and SMB clear and
+// set one bit of a zero page byte, the bit number is in the opcode
+
+instruction!(RMB, vec![0x07, 0x17, 0x27, 0x37, 0x47, 0x57, 0x67, 0x77],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let bit = (*opcode >> 4) & 0x07;
+        let (address, value, _) = Mode::Zeropage.get_memory(cpu);
+        cpu.set_memory_at_address(address, value & !(1 << bit));
         true
     }
 );
-instruction!(INC, vec![0xE6, 0xF6],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
+instruction!(SMB, vec![0x87, 0x97, 0xA7, 0xB7, 0xC7, 0xD7, 0xE7, 0xF7],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        let bit = (*opcode >> 4) & 0x07;
+        let (address, value, _) = Mode::Zeropage.get_memory(cpu);
+        cpu.set_memory_at_address(address, value | (1 << bit));
         true
     }
 );
+instruction!(BBR, vec![0x0F, 0x1F, 0x2F, 0x3F, 0x4F, 0x5F, 0x6F, 0x7F],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        branch_on_bit(*opcode, cpu, false)
+    }
+);
+instruction!(BBS, vec![0x8F, 0x9F, 0xAF, 0xBF, 0xCF, 0xDF, 0xEF, 0xFF],
+    fn execute(&self, opcode: &u8, cpu: &mut CPU<B>) -> bool {
+        branch_on_bit(*opcode, cpu, true)
+    }
+);
 
-instruction!(NOP, vec![0xEA],
-    fn execute(&self, opcode: &i16, cpu: &mut CPU) -> bool {
-        true
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{NextPc, Variant};
+
+    // A CPU with the program loaded at $0200 and PC pointing at it
+    fn cpu_with(program: &[u8]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_program(0x0200, program).unwrap();
+        cpu.set_pc(0x0200);
+        cpu
+    }
+
+    #[test]
+    fn php_plp_round_trip_leaves_break_clear() {
+        let mut cpu = cpu_with(&[0x08, 0x28]);
+        cpu.set_flag(Flag::Carry, true);
+        cpu.step();
+        assert_eq!(cpu.peek_stack(0), 0x31);
+        cpu.step();
+        assert!(!cpu.registers().sr.sbreak);
+        assert!(cpu.registers().sr.carry);
+    }
+
+    #[test]
+    #[should_panic(expected = "Opcode A9 is registered by more than one instruction")]
+    fn registering_an_opcode_twice_panics() {
+        let instructions: Vec<Box<dyn Instruction>> = vec![Box::new(LDA::new()), Box::new(LDA::new())];
+        dispatch_table(&instructions);
+    }
+
+    #[test]
+    fn both_instruction_sets_build_their_tables() {
+        let table = dispatch_table(&init_instructions::<RamBus>());
+        assert!(table[0xA9].is_some());
+        let table = dispatch_table(&init_cmos_instructions::<RamBus>());
+        assert!(table[0xB7].is_some());
+    }
+
+    #[test]
+    fn pla_sets_negative() {
+        let mut cpu = cpu_with(&[0x68]);
+        cpu.push_to_stack(0x80);
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0x80);
+        assert!(cpu.registers().sr.negative);
+        assert!(!cpu.registers().sr.zero);
+    }
+
+    #[test]
+    fn plp_forces_bit_five_and_ignores_break() {
+        for pulled in [0x00u8, 0x10, 0x20, 0x30, 0xC3, 0xD3] {
+            let mut cpu = cpu_with(&[0x28]);
+            cpu.push_to_stack(pulled);
+            cpu.step();
+            assert_eq!(u8::from(cpu.registers().sr), (pulled & !0x10) | 0x20);
+        }
     }
-);
\ No newline at end of file
+
+    #[test]
+    fn decimal_adc_zero_flag_comes_from_the_binary_sum() {
+        // 99 + 67 is 166 in BCD, but $99 + $67 is $100 in binary
+        let mut cpu = cpu_with(&[0xF8, 0x69, 0x67]);
+        cpu.set_ac(0x99);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0x66);
+        assert!(cpu.registers().sr.carry);
+        assert!(cpu.registers().sr.zero);
+    }
+
+    #[test]
+    fn anc_copies_bit_seven_into_carry() {
+        let mut cpu = cpu_with(&[0x0B, 0x80]);
+        cpu.illegal_opcodes = true;
+        cpu.set_ac(0xFF);
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0x80);
+        assert!(cpu.registers().sr.carry);
+        assert!(cpu.registers().sr.negative);
+    }
+
+    #[test]
+    fn arr_rotates_then_takes_c_and_v_from_bits_six_and_five() {
+        let mut cpu = cpu_with(&[0x6B, 0x80]);
+        cpu.illegal_opcodes = true;
+        cpu.set_ac(0xFF);
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0x40);
+        assert!(cpu.registers().sr.carry);
+        assert!(cpu.registers().sr.overflow);
+        assert!(!cpu.registers().sr.negative);
+    }
+
+    #[test]
+    fn jsr_takes_six_cycles_and_pushes_high_then_low() {
+        let mut cpu = CPU::new();
+        cpu.load_program(0x12F0, &[0x20, 0x00, 0x30]).unwrap();
+        cpu.set_pc(0x12F0);
+        cpu.set_sp(0xFF);
+        assert_eq!(cpu.step(), 6);
+        assert_eq!(cpu.registers().pc, 0x3000);
+        // The address of the JSR's last byte, RTS adds the one
+        assert_eq!(cpu.peek(0x01FF), 0x12);
+        assert_eq!(cpu.peek(0x01FE), 0xF2);
+        assert_eq!(cpu.registers().sp, 0xFD);
+    }
+
+    // A 65C02 with the program loaded at $0200
+    fn cmos_with(program: &[u8]) -> CPU {
+        let mut cpu = cpu_with(program);
+        cpu.variant = Variant::Cmos;
+        cpu
+    }
+
+    #[test]
+    fn rmb3_clears_only_bit_three() {
+        let mut cpu = cmos_with(&[0x37, 0x10]);
+        cpu.poke(0x0010, 0xFF);
+        assert_eq!(cpu.step(), 5);
+        assert_eq!(cpu.peek(0x0010), 0xF7);
+        assert_eq!(cpu.registers().pc, 0x0202);
+    }
+
+    #[test]
+    fn smb3_sets_only_bit_three() {
+        let mut cpu = cmos_with(&[0xB7, 0x10]);
+        cpu.poke(0x0010, 0x00);
+        cpu.step();
+        assert_eq!(cpu.peek(0x0010), 0x08);
+    }
+
+    #[test]
+    fn bbs3_branches_when_the_bit_is_set() {
+        let mut cpu = cmos_with(&[0xBF, 0x10, 0x05]);
+        cpu.poke(0x0010, 0x08);
+        cpu.step();
+        assert_eq!(cpu.registers().pc, 0x0208);
+        let mut cpu = cmos_with(&[0xBF, 0x10, 0x05]);
+        cpu.poke(0x0010, 0xF7);
+        cpu.step();
+        assert_eq!(cpu.registers().pc, 0x0203);
+    }
+
+    #[test]
+    fn bbr3_branches_when_the_bit_is_clear() {
+        let mut cpu = cmos_with(&[0x3F, 0x10, 0xFD]);
+        cpu.poke(0x0010, 0xF7);
+        cpu.step();
+        assert_eq!(cpu.registers().pc, 0x0200);
+    }
+
+    #[test]
+    fn bit_instructions_disassemble_and_predict_on_cmos() {
+        let mut cpu = cmos_with(&[0xB7, 0x10, 0x3F, 0x10, 0xFD]);
+        let lines: Vec<String> = cpu.disassemble(0x0200).take(2).map(|(_, _, text)| text).collect();
+        assert_eq!(lines, vec!["SMB3 $10", "BBR3 $10,$0202"]);
+        cpu.set_pc(0x0202);
+        assert_eq!(cpu.next_pc(), NextPc::Branch { taken: 0x0202, not_taken: 0x0205 });
+        cpu.poke(0x0010, 0x00);
+        assert!(cpu.is_at_infinite_loop());
+        // The same bytes are illegal opcodes on NMOS
+        cpu.variant = Variant::Nmos;
+        assert_eq!(cpu.disassemble(0x0200).next().unwrap().2, "*LAX $10,Y");
+    }
+
+    #[test]
+    fn branch_not_taken_costs_two_cycles() {
+        let mut cpu = cpu_with(&[0xB0, 0x10]);
+        cpu.set_flag(Flag::Carry, false);
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.registers().pc, 0x0202);
+    }
+
+    #[test]
+    fn bit_takes_n_and_v_from_the_operand_even_when_z_is_set() {
+        let mut cpu = cpu_with(&[0x24, 0x10]);
+        cpu.poke(0x0010, 0xC0);
+        cpu.set_ac(0x00);
+        cpu.step();
+        assert!(cpu.registers().sr.zero);
+        assert!(cpu.registers().sr.negative);
+        assert!(cpu.registers().sr.overflow);
+    }
+
+    #[test]
+    fn slo_shifts_memory_then_ors_it_in() {
+        let mut cpu = cpu_with(&[0x07, 0x10]);
+        cpu.illegal_opcodes = true;
+        cpu.poke(0x0010, 0x81);
+        cpu.set_ac(0x01);
+        cpu.step();
+        assert_eq!(cpu.peek(0x0010), 0x02);
+        assert_eq!(cpu.registers().ac, 0x03);
+        assert!(cpu.registers().sr.carry);
+        assert!(!cpu.registers().sr.zero);
+    }
+
+    #[test]
+    fn dcp_decrements_memory_then_compares() {
+        let mut cpu = cpu_with(&[0xC7, 0x10]);
+        cpu.illegal_opcodes = true;
+        cpu.poke(0x0010, 0x05);
+        cpu.set_ac(0x04);
+        cpu.step();
+        assert_eq!(cpu.peek(0x0010), 0x04);
+        assert_eq!(cpu.registers().ac, 0x04);
+        assert!(cpu.registers().sr.zero);
+        assert!(cpu.registers().sr.carry);
+    }
+
+    #[test]
+    fn compare_ignores_decimal_mode() {
+        let mut cpu = cpu_with(&[0xC9, 0x01, 0xE0, 0x50, 0xC0, 0x0A]);
+        cpu.set_flag(Flag::Decimal, true);
+        cpu.set_ac(0x00);
+        cpu.set_x(0x50);
+        cpu.set_y(0x10);
+        // $00 - $01 is $FF in binary
+        cpu.step();
+        assert_eq!((cpu.registers().sr.negative, cpu.registers().sr.zero, cpu.registers().sr.carry), (true, false, false));
+        cpu.step();
+        assert_eq!((cpu.registers().sr.negative, cpu.registers().sr.zero, cpu.registers().sr.carry), (false, true, true));
+        // $0A isn't a BCD number, binary has no problem with it
+        cpu.step();
+        assert_eq!((cpu.registers().sr.negative, cpu.registers().sr.zero, cpu.registers().sr.carry), (false, false, true));
+        assert_eq!(cpu.registers().ac, 0x00);
+    }
+
+    #[test]
+    fn indexed_indirect_never_pays_for_a_page_cross() {
+        // $1F+$01 and $21+$FF both land on the pointer at $20, the second by
+        // wrapping inside zero page
+        let mut cpu = cpu_with(&[0xA1, 0x1F, 0xA1, 0x21]);
+        cpu.write16(0x0020, 0x30FF);
+        cpu.set_x(0x01);
+        assert_eq!(cpu.step(), 6);
+        cpu.set_x(0xFF);
+        assert_eq!(cpu.step(), 6);
+    }
+
+    #[test]
+    fn indirect_indexed_pays_for_a_page_cross() {
+        let mut cpu = cpu_with(&[0xB1, 0x20, 0xB1, 0x20]);
+        cpu.write16(0x0020, 0x30FF);
+        cpu.set_y(0x00);
+        assert_eq!(cpu.step(), 5);
+        cpu.set_y(0x01);
+        assert_eq!(cpu.step(), 6);
+    }
+
+    #[test]
+    fn lda_of_a_negative_value_sets_n() {
+        let mut cpu = cpu_with(&[0xA9, 0x80]);
+        cpu.step();
+        assert!(cpu.registers().sr.negative);
+        assert!(!cpu.registers().sr.zero);
+    }
+
+    #[test]
+    fn adc_of_two_positives_into_negative_sets_v() {
+        let mut cpu = cpu_with(&[0x69, 0x50]);
+        cpu.set_ac(0x50);
+        cpu.set_flag(Flag::Carry, false);
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0xA0);
+        assert!(cpu.registers().sr.overflow);
+        assert!(cpu.registers().sr.negative);
+        assert!(!cpu.registers().sr.carry);
+    }
+
+    #[test]
+    fn sbc_across_the_sign_boundary_sets_v() {
+        let mut cpu = cpu_with(&[0xE9, 0xB0]);
+        cpu.set_ac(0x50);
+        cpu.set_flag(Flag::Carry, true);
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0xA0);
+        assert!(cpu.registers().sr.overflow);
+        // $50 - $B0 borrows
+        assert!(!cpu.registers().sr.carry);
+    }
+
+    #[test]
+    fn zeropage_load_takes_one_operand_byte() {
+        // LDA $10 then a NOP, PC has to land on the NOP
+        let mut cpu = cpu_with(&[0xA5, 0x10, 0xEA]);
+        cpu.poke(0x0010, 0x42);
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0x42);
+        assert_eq!(cpu.registers().pc, 0x0202);
+    }
+
+    #[test]
+    fn sta_absolute_reads_back() {
+        // STA $5000, LDA #$00, LDA $5000
+        let mut cpu = cpu_with(&[0x8D, 0x00, 0x50, 0xA9, 0x00, 0xAD, 0x00, 0x50]);
+        cpu.set_ac(0x5A);
+        cpu.step();
+        assert_eq!(cpu.peek(0x5000), 0x5A);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0x5A);
+        assert_eq!(cpu.registers().pc, 0x0208);
+    }
+
+    #[test]
+    fn indexed_read_pays_for_a_page_cross() {
+        let mut cpu = cpu_with(&[0xBD, 0xFF, 0x30, 0xBD, 0xFF, 0x30]);
+        cpu.set_x(0x00);
+        assert_eq!(cpu.step(), 4);
+        cpu.set_x(0x01);
+        assert_eq!(cpu.step(), 5);
+    }
+
+    #[test]
+    fn asl_a_doubles_the_accumulator() {
+        let mut cpu = cpu_with(&[0x0A]);
+        cpu.set_ac(0x21);
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.registers().ac, 0x42);
+        assert!(!cpu.registers().sr.carry);
+        // No operand to skip over
+        assert_eq!(cpu.registers().pc, 0x0201);
+        // Straight through the mode as well
+        let (address, value, _) = Mode::A.get_memory(&mut cpu);
+        Mode::A.set_memory(&mut cpu, address, value << 1);
+        assert_eq!(cpu.registers().ac, 0x84);
+    }
+
+    #[test]
+    fn logical_ops_set_z_and_n_from_the_result() {
+        // (immediate opcode, zero page,X opcode, A, operand, result)
+        let cases = [
+            (0x09, 0x15, 0x00, 0x00, 0x00),
+            (0x09, 0x15, 0x01, 0x80, 0x81),
+            (0x29, 0x35, 0xF0, 0x0F, 0x00),
+            (0x29, 0x35, 0xFF, 0x80, 0x80),
+            (0x49, 0x55, 0x5A, 0x5A, 0x00),
+            (0x49, 0x55, 0x7F, 0xFF, 0x80),
+        ];
+        for (immediate, indexed, ac, operand, result) in cases {
+            let mut immediate = cpu_with(&[immediate, operand]);
+            let mut indexed = cpu_with(&[indexed, 0x10]);
+            indexed.set_x(0x02);
+            indexed.poke(0x0012, operand);
+            for cpu in [&mut immediate, &mut indexed] {
+                cpu.set_ac(ac);
+                // Start from the opposite flags so the instruction has to set them
+                cpu.set_status(StatRegister::from(if result == 0 { 0x80 } else { 0x02 }));
+                cpu.step();
+                assert_eq!(cpu.registers().ac, result);
+                assert_eq!(cpu.registers().sr.zero, result == 0, "Z for {:02X}", cpu.peek(0x0200));
+                assert_eq!(cpu.registers().sr.negative, result & 0x80 != 0, "N for {:02X}", cpu.peek(0x0200));
+            }
+        }
+    }
+
+    #[test]
+    fn stores_leave_n_and_z_alone() {
+        // STA $10, STX $11, STY $12 of zero with N set and Z clear
+        let mut cpu = cpu_with(&[0x85, 0x10, 0x86, 0x11, 0x84, 0x12]);
+        cpu.set_ac(0x00);
+        cpu.set_x(0x00);
+        cpu.set_y(0x00);
+        cpu.set_status(StatRegister::from(0x80));
+        for address in 0x0010..0x0013 {
+            cpu.step();
+            assert_eq!(cpu.peek(address), 0x00);
+            assert!(cpu.registers().sr.negative);
+            assert!(!cpu.registers().sr.zero);
+        }
+    }
+
+    #[test]
+    fn pushed_byte_loads_back_from_page_one() {
+        // PHA, LDA #$00, LDA $01FF
+        let mut cpu = cpu_with(&[0x48, 0xA9, 0x00, 0xAD, 0xFF, 0x01]);
+        cpu.set_sp(0xFF);
+        cpu.set_ac(0x5A);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0x00);
+        cpu.step();
+        assert_eq!(cpu.registers().ac, 0x5A);
+    }
+
+    #[test]
+    fn modes_display_in_standard_notation() {
+        assert_eq!(Mode::AbsoluteX.to_string(), "Absolute,X");
+        assert_eq!(Mode::IndirectY.to_string(), "(Indirect),Y");
+        assert_eq!(Mode::IndirectX.to_string(), "(Indirect,X)");
+        assert_eq!(Mode::Immediate.to_string(), "Immediate");
+        assert_eq!(format!("{:?}", Mode::ZeropageY), "ZeropageY");
+    }
+
+    #[test]
+    fn dec_and_inc_change_memory_and_set_n_and_z() {
+        // DEC $10, INC $10, INC $3000,X
+        let mut cpu = cpu_with(&[0xC6, 0x10, 0xE6, 0x10, 0xFE, 0x00, 0x30]);
+        cpu.poke(0x0010, 0x00);
+        assert_eq!(cpu.step(), 5);
+        assert_eq!(cpu.peek(0x0010), 0xFF);
+        assert_eq!(cpu.registers().pc, 0x0202);
+        assert!(cpu.registers().sr.negative);
+        cpu.step();
+        assert_eq!(cpu.peek(0x0010), 0x00);
+        assert!(cpu.registers().sr.zero);
+        cpu.set_x(0x05);
+        cpu.poke(0x3005, 0x41);
+        assert_eq!(cpu.step(), 7);
+        assert_eq!(cpu.peek(0x3005), 0x42);
+        assert_eq!(cpu.registers().pc, 0x0207);
+    }
+
+    #[test]
+    fn isc_increments_memory_then_subtracts() {
+        let mut cpu = cpu_with(&[0xE7, 0x10]);
+        cpu.illegal_opcodes = true;
+        cpu.poke(0x0010, 0x04);
+        cpu.set_ac(0x10);
+        cpu.set_flag(Flag::Carry, true);
+        cpu.step();
+        assert_eq!(cpu.peek(0x0010), 0x05);
+        assert_eq!(cpu.registers().ac, 0x0B);
+        assert!(cpu.registers().sr.carry);
+    }
+
+    #[test]
+    fn rti_from_a_brk_leaves_break_clear() {
+        let mut cpu = cpu_with(&[0x00, 0xFF]);
+        cpu.load_program(0x0300, &[0x40]).unwrap();
+        cpu.set_irq_vector(0x0300);
+        cpu.set_flag(Flag::Carry, true);
+        cpu.step();
+        // The copy on the stack has B set
+        assert_eq!(cpu.peek_stack(0), 0x31);
+        cpu.step();
+        assert_eq!(cpu.registers().pc, 0x0202);
+        assert!(!cpu.registers().sr.sbreak);
+        assert_eq!(u8::from(cpu.registers().sr), 0x21);
+    }
+}
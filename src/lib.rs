@@ -0,0 +1,17 @@
+pub mod bus;
+pub mod cpu;
+pub mod devices;
+pub mod disasm;
+#[cfg(test)]
+mod fuzz;
+pub mod instructions;
+pub mod opcodes;
+pub mod rng;
+pub mod rom;
+pub mod trace;
+#[cfg(feature = "tui")]
+pub mod tui;
+
+pub use bus::{Bus, RamBus};
+pub use cpu::{CPU, Registers, StatRegister};
+pub use instructions::{Instruction, Mode};
@@ -1,18 +1,25 @@
-use cpu::CPU;
-
-mod cpu;
-mod instructions;
-
+use grey6502::{cpu, CPU};
+#[cfg(feature = "tui")]
+use grey6502::tui;
 
 fn main() {
     let mut cpu = CPU::new();
-    let memory_lock = cpu.memory.clone();
-    let mut mem = memory_lock.lock().unwrap();
-    mem[2] = 0xA0;
-    mem[3] = 0x05;
+    // An assembled program can be piped in, EG. grey6502 --stdin < program.bin
+    if std::env::args().any(|arg| arg == "--stdin") {
+        let loaded = cpu.load_from_reader(&mut std::io::stdin(), cpu::PROGRAM_START)
+            .expect("Failed to load the program from stdin");
+        println!("Loaded {} bytes at ${:04X}", loaded, cpu::PROGRAM_START);
+        cpu.set_reset_vector(cpu::PROGRAM_START);
+    } else {
+        // LDY #5 and a BPL back to itself, everything around them is the NOP fill
+        cpu.load_segments(&[(0x0002, &[0xA0, 0x05][..]), (0x000A, &[0x10, 0xFE][..])])
+            .expect("Failed to load the program");
+        cpu.set_reset_vector(0x0000);
+    }
+    cpu.reset();
 
-    mem[10] = 0x10;
-    mem[11] = -0x02;
-    drop(mem);
+    #[cfg(feature = "tui")]
+    tui::Viewer::new().run(&mut cpu).expect("Viewer failed");
+    #[cfg(not(feature = "tui"))]
     cpu.run();
 }
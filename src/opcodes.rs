@@ -0,0 +1,228 @@
+use crate::{cpu::Variant, instructions::Mode};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Opcode {
+    pub mnemonic: &'static str,
+    pub mode: Mode,
+    // Undocumented, these only do something sensible on NMOS chips
+    pub illegal: bool,
+}
+
+const fn op(mnemonic: &'static str, mode: Mode) -> Opcode {
+    Opcode { mnemonic, mode, illegal: false }
+}
+
+const fn ill(mnemonic: &'static str, mode: Mode) -> Opcode {
+    Opcode { mnemonic, mode, illegal: true }
+}
+
+// Every opcode byte on the NMOS 6502, indexed by the opcode. The JAM opcodes
+// lock the processor up rather than doing anything
+pub const OPCODES: [Opcode; 256] = [
+    // 0_
+    op("BRK", Mode::Implied), op("ORA", Mode::IndirectX), ill("JAM", Mode::Implied), ill("SLO", Mode::IndirectX),
+    ill("NOP", Mode::Zeropage), op("ORA", Mode::Zeropage), op("ASL", Mode::Zeropage), ill("SLO", Mode::Zeropage),
+    op("PHP", Mode::Implied), op("ORA", Mode::Immediate), op("ASL", Mode::A), ill("ANC", Mode::Immediate),
+    ill("NOP", Mode::Absolute), op("ORA", Mode::Absolute), op("ASL", Mode::Absolute), ill("SLO", Mode::Absolute),
+    // 1_
+    op("BPL", Mode::Relative), op("ORA", Mode::IndirectY), ill("JAM", Mode::Implied), ill("SLO", Mode::IndirectY),
+    ill("NOP", Mode::ZeropageX), op("ORA", Mode::ZeropageX), op("ASL", Mode::ZeropageX), ill("SLO", Mode::ZeropageX),
+    op("CLC", Mode::Implied), op("ORA", Mode::AbsoluteY), ill("NOP", Mode::Implied), ill("SLO", Mode::AbsoluteY),
+    ill("NOP", Mode::AbsoluteX), op("ORA", Mode::AbsoluteX), op("ASL", Mode::AbsoluteX), ill("SLO", Mode::AbsoluteX),
+    // 2_
+    op("JSR", Mode::Absolute), op("AND", Mode::IndirectX), ill("JAM", Mode::Implied), ill("RLA", Mode::IndirectX),
+    op("BIT", Mode::Zeropage), op("AND", Mode::Zeropage), op("ROL", Mode::Zeropage), ill("RLA", Mode::Zeropage),
+    op("PLP", Mode::Implied), op("AND", Mode::Immediate), op("ROL", Mode::A), ill("ANC", Mode::Immediate),
+    op("BIT", Mode::Absolute), op("AND", Mode::Absolute), op("ROL", Mode::Absolute), ill("RLA", Mode::Absolute),
+    // 3_
+    op("BMI", Mode::Relative), op("AND", Mode::IndirectY), ill("JAM", Mode::Implied), ill("RLA", Mode::IndirectY),
+    ill("NOP", Mode::ZeropageX), op("AND", Mode::ZeropageX), op("ROL", Mode::ZeropageX), ill("RLA", Mode::ZeropageX),
+    op("SEC", Mode::Implied), op("AND", Mode::AbsoluteY), ill("NOP", Mode::Implied), ill("RLA", Mode::AbsoluteY),
+    ill("NOP", Mode::AbsoluteX), op("AND", Mode::AbsoluteX), op("ROL", Mode::AbsoluteX), ill("RLA", Mode::AbsoluteX),
+    // 4_
+    op("RTI", Mode::Implied), op("EOR", Mode::IndirectX), ill("JAM", Mode::Implied), ill("SRE", Mode::IndirectX),
+    ill("NOP", Mode::Zeropage), op("EOR", Mode::Zeropage), op("LSR", Mode::Zeropage), ill("SRE", Mode::Zeropage),
+    op("PHA", Mode::Implied), op("EOR", Mode::Immediate), op("LSR", Mode::A), ill("ALR", Mode::Immediate),
+    op("JMP", Mode::Absolute), op("EOR", Mode::Absolute), op("LSR", Mode::Absolute), ill("SRE", Mode::Absolute),
+    // 5_
+    op("BVC", Mode::Relative), op("EOR", Mode::IndirectY), ill("JAM", Mode::Implied), ill("SRE", Mode::IndirectY),
+    ill("NOP", Mode::ZeropageX), op("EOR", Mode::ZeropageX), op("LSR", Mode::ZeropageX), ill("SRE", Mode::ZeropageX),
+    op("CLI", Mode::Implied), op("EOR", Mode::AbsoluteY), ill("NOP", Mode::Implied), ill("SRE", Mode::AbsoluteY),
+    ill("NOP", Mode::AbsoluteX), op("EOR", Mode::AbsoluteX), op("LSR", Mode::AbsoluteX), ill("SRE", Mode::AbsoluteX),
+    // 6_
+    op("RTS", Mode::Implied), op("ADC", Mode::IndirectX), ill("JAM", Mode::Implied), ill("RRA", Mode::IndirectX),
+    ill("NOP", Mode::Zeropage), op("ADC", Mode::Zeropage), op("ROR", Mode::Zeropage), ill("RRA", Mode::Zeropage),
+    op("PLA", Mode::Implied), op("ADC", Mode::Immediate), op("ROR", Mode::A), ill("ARR", Mode::Immediate),
+    op("JMP", Mode::Indirect), op("ADC", Mode::Absolute), op("ROR", Mode::Absolute), ill("RRA", Mode::Absolute),
+    // 7_
+    op("BVS", Mode::Relative), op("ADC", Mode::IndirectY), ill("JAM", Mode::Implied), ill("RRA", Mode::IndirectY),
+    ill("NOP", Mode::ZeropageX), op("ADC", Mode::ZeropageX), op("ROR", Mode::ZeropageX), ill("RRA", Mode::ZeropageX),
+    op("SEI", Mode::Implied), op("ADC", Mode::AbsoluteY), ill("NOP", Mode::Implied), ill("RRA", Mode::AbsoluteY),
+    ill("NOP", Mode::AbsoluteX), op("ADC", Mode::AbsoluteX), op("ROR", Mode::AbsoluteX), ill("RRA", Mode::AbsoluteX),
+    // 8_
+    ill("NOP", Mode::Immediate), op("STA", Mode::IndirectX), ill("NOP", Mode::Immediate), ill("SAX", Mode::IndirectX),
+    op("STY", Mode::Zeropage), op("STA", Mode::Zeropage), op("STX", Mode::Zeropage), ill("SAX", Mode::Zeropage),
+    op("DEY", Mode::Implied), ill("NOP", Mode::Immediate), op("TXA", Mode::Implied), ill("XAA", Mode::Immediate),
+    op("STY", Mode::Absolute), op("STA", Mode::Absolute), op("STX", Mode::Absolute), ill("SAX", Mode::Absolute),
+    // 9_
+    op("BCC", Mode::Relative), op("STA", Mode::IndirectY), ill("JAM", Mode::Implied), ill("AHX", Mode::IndirectY),
+    op("STY", Mode::ZeropageX), op("STA", Mode::ZeropageX), op("STX", Mode::ZeropageY), ill("SAX", Mode::ZeropageY),
+    op("TYA", Mode::Implied), op("STA", Mode::AbsoluteY), op("TXS", Mode::Implied), ill("TAS", Mode::AbsoluteY),
+    ill("SHY", Mode::AbsoluteX), op("STA", Mode::AbsoluteX), ill("SHX", Mode::AbsoluteY), ill("AHX", Mode::AbsoluteY),
+    // A_
+    op("LDY", Mode::Immediate), op("LDA", Mode::IndirectX), op("LDX", Mode::Immediate), ill("LAX", Mode::IndirectX),
+    op("LDY", Mode::Zeropage), op("LDA", Mode::Zeropage), op("LDX", Mode::Zeropage), ill("LAX", Mode::Zeropage),
+    op("TAY", Mode::Implied), op("LDA", Mode::Immediate), op("TAX", Mode::Implied), ill("LXA", Mode::Immediate),
+    op("LDY", Mode::Absolute), op("LDA", Mode::Absolute), op("LDX", Mode::Absolute), ill("LAX", Mode::Absolute),
+    // B_
+    op("BCS", Mode::Relative), op("LDA", Mode::IndirectY), ill("JAM", Mode::Implied), ill("LAX", Mode::IndirectY),
+    op("LDY", Mode::ZeropageX), op("LDA", Mode::ZeropageX), op("LDX", Mode::ZeropageY), ill("LAX", Mode::ZeropageY),
+    op("CLV", Mode::Implied), op("LDA", Mode::AbsoluteY), op("TSX", Mode::Implied), ill("LAS", Mode::AbsoluteY),
+    op("LDY", Mode::AbsoluteX), op("LDA", Mode::AbsoluteX), op("LDX", Mode::AbsoluteY), ill("LAX", Mode::AbsoluteY),
+    // C_
+    op("CPY", Mode::Immediate), op("CMP", Mode::IndirectX), ill("NOP", Mode::Immediate), ill("DCP", Mode::IndirectX),
+    op("CPY", Mode::Zeropage), op("CMP", Mode::Zeropage), op("DEC", Mode::Zeropage), ill("DCP", Mode::Zeropage),
+    op("INY", Mode::Implied), op("CMP", Mode::Immediate), op("DEX", Mode::Implied), ill("AXS", Mode::Immediate),
+    op("CPY", Mode::Absolute), op("CMP", Mode::Absolute), op("DEC", Mode::Absolute), ill("DCP", Mode::Absolute),
+    // D_
+    op("BNE", Mode::Relative), op("CMP", Mode::IndirectY), ill("JAM", Mode::Implied), ill("DCP", Mode::IndirectY),
+    ill("NOP", Mode::ZeropageX), op("CMP", Mode::ZeropageX), op("DEC", Mode::ZeropageX), ill("DCP", Mode::ZeropageX),
+    op("CLD", Mode::Implied), op("CMP", Mode::AbsoluteY), ill("NOP", Mode::Implied), ill("DCP", Mode::AbsoluteY),
+    ill("NOP", Mode::AbsoluteX), op("CMP", Mode::AbsoluteX), op("DEC", Mode::AbsoluteX), ill("DCP", Mode::AbsoluteX),
+    // E_
+    op("CPX", Mode::Immediate), op("SBC", Mode::IndirectX), ill("NOP", Mode::Immediate), ill("ISC", Mode::IndirectX),
+    op("CPX", Mode::Zeropage), op("SBC", Mode::Zeropage), op("INC", Mode::Zeropage), ill("ISC", Mode::Zeropage),
+    op("INX", Mode::Implied), op("SBC", Mode::Immediate), op("NOP", Mode::Implied), ill("SBC", Mode::Immediate),
+    op("CPX", Mode::Absolute), op("SBC", Mode::Absolute), op("INC", Mode::Absolute), ill("ISC", Mode::Absolute),
+    // F_
+    op("BEQ", Mode::Relative), op("SBC", Mode::IndirectY), ill("JAM", Mode::Implied), ill("ISC", Mode::IndirectY),
+    ill("NOP", Mode::ZeropageX), op("SBC", Mode::ZeropageX), op("INC", Mode::ZeropageX), ill("ISC", Mode::ZeropageX),
+    op("SED", Mode::Implied), op("SBC", Mode::AbsoluteY), ill("NOP", Mode::Implied), ill("ISC", Mode::AbsoluteY),
+    ill("NOP", Mode::AbsoluteX), op("SBC", Mode::AbsoluteX), op("INC", Mode::AbsoluteX), ill("ISC", Mode::AbsoluteX),
+];
+
+pub fn decode(opcode: u8) -> (&'static str, Mode) {
+    let info = OPCODES[opcode as usize];
+    (info.mnemonic, info.mode)
+}
+
+// The three letter mnemonic, EG. "LDA" or "LAX" for illegal opcodes.
+// Every byte is something on NMOS, the ones that lock up are "JAM"
+pub fn mnemonic(opcode: u8) -> &'static str {
+    OPCODES[opcode as usize].mnemonic
+}
+
+pub fn is_illegal(opcode: u8) -> bool {
+    OPCODES[opcode as usize].illegal
+}
+
+const RMB: [&str; 8] = ["RMB0", "RMB1", "RMB2", "RMB3", "RMB4", "RMB5", "RMB6", "RMB7"];
+const SMB: [&str; 8] = ["SMB0", "SMB1", "SMB2", "SMB3", "SMB4", "SMB5", "SMB6", "SMB7"];
+const BBR: [&str; 8] = ["BBR0", "BBR1", "BBR2", "BBR3", "BBR4", "BBR5", "BBR6", "BBR7"];
+const BBS: [&str; 8] = ["BBS0", "BBS1", "BBS2", "BBS3", "BBS4", "BBS5", "BBS6", "BBS7"];
+
+// The 65C02 bit instructions sit on top of NMOS illegal opcodes, the high
+// nibble picks the bit and whether it's set/reset, EG. $B7 is SMB3
+fn decode_cmos(opcode: u8) -> Option<(&'static str, Mode)> {
+    let bit = ((opcode >> 4) & 0x07) as usize;
+    let set = opcode & 0x80 != 0;
+    match opcode & 0x0F {
+        0x07 => Some((if set { SMB[bit] } else { RMB[bit] }, Mode::Zeropage)),
+        0x0F => Some((if set { BBS[bit] } else { BBR[bit] }, Mode::ZeropageRelative)),
+        _ => None,
+    }
+}
+
+// Same as decode but for the given chip, the opcode tables differ on CMOS
+pub fn decode_for(opcode: u8, variant: Variant) -> (&'static str, Mode) {
+    match variant {
+        Variant::Cmos => decode_cmos(opcode).unwrap_or_else(|| decode(opcode)),
+        Variant::Nmos => decode(opcode),
+    }
+}
+
+pub fn is_illegal_for(opcode: u8, variant: Variant) -> bool {
+    match variant {
+        Variant::Cmos => decode_cmos(opcode).is_none() && is_illegal(opcode),
+        Variant::Nmos => is_illegal(opcode),
+    }
+}
+
+// How an opcode uses the memory its mode addresses. Indexed reads only pay
+// for a page crossing when it happens, writes and read-modify-writes always
+// take the extra cycle so it's already in their base cost
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    None,
+    Read,
+    Write,
+    ReadModifyWrite,
+}
+
+pub fn access(opcode: u8) -> Access {
+    let (mnemonic, mode) = decode(opcode);
+    if let Mode::A | Mode::Implied | Mode::Relative = mode {
+        return Access::None;
+    }
+    match mnemonic {
+        "STA" | "STX" | "STY" | "SAX" | "AHX" | "SHX" | "SHY" | "TAS" => Access::Write,
+        "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC" |
+        "SLO" | "RLA" | "SRE" | "RRA" | "DCP" | "ISC" => Access::ReadModifyWrite,
+        "JMP" | "JSR" => Access::None,
+        _ => Access::Read,
+    }
+}
+
+// Base cycle cost of every opcode, illegal ones included, before page crossing
+// and branch penalties. The JAM opcodes never finish so they're 0
+pub const CYCLES: [u8; 256] = [
+    7, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6, // 0_
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 1_
+    6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6, // 2_
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 3_
+    6, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6, // 4_
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 5_
+    6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6, // 6_
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 7_
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // 8_
+    2, 6, 0, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5, // 9_
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // A_
+    2, 5, 0, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4, // B_
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // C_
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // D_
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // E_
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // F_
+];
+
+// The 65C02 opcodes whose cost differs from the table above. Only covers the
+// ones the CMOS variant implements, RMB/SMB and BBR/BBS are all 5 before the
+// branch penalties
+pub fn cmos_cycles(opcode: u8) -> Option<u8> {
+    match opcode & 0x0F {
+        0x07 | 0x0F => Some(5),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_cycle_counts() {
+        assert_eq!(CYCLES[0xEA], 2);
+        assert_eq!(CYCLES[0x20], 6);
+        assert_eq!(CYCLES[0xBD], 4);
+        // Illegal opcodes have entries too
+        assert_eq!(CYCLES[0xA7], 3);
+    }
+
+    #[test]
+    fn mnemonics_cover_legal_and_illegal_opcodes() {
+        assert_eq!(mnemonic(0xA9), "LDA");
+        assert_eq!(mnemonic(0xA7), "LAX");
+        assert!(is_illegal(0xA7));
+        // The lock up opcodes have a name too rather than "???"
+        assert_eq!(mnemonic(0x02), "JAM");
+    }
+}
@@ -0,0 +1,22 @@
+// Small xorshift generator so runs can be repeated from the seed alone
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        // Zero would get stuck at zero forever
+        Self(if seed == 0 { 0x2545_F491_4F6C_DD1D } else { seed })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 32) as u8
+    }
+}
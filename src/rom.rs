@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use crate::{bus::Bus, cpu::CPU};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    // Line numbers start at 1 to match what an editor shows
+    Parse { line: usize, message: String },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Parse { line, message } => write!(f, "Line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn is_hex_byte(token: &str) -> bool {
+    token.len() == 2 && token.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Loads an assembler listing into memory and returns its labels, so the
+// program can be run and disassembled with names in one go. Lines look like
+//  C000  A9 00     start:  LDA #$00
+// an address, the assembled bytes, then the source. A label can also sit on
+// its own line, in which case it names the next address. Comments start with ;
+pub fn load_listing<B: Bus>(cpu: &mut CPU<B>, text: &str) -> Result<HashMap<u16, String>, Error> {
+    let mut symbols = HashMap::new();
+    let mut pending_labels: Vec<String> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or("");
+        let mut tokens = line.split_whitespace().peekable();
+        let first = match tokens.peek() {
+            Some(token) => *token,
+            None => continue,
+        };
+
+        // A trailing colon makes it a label, even one like beef: that would
+        // otherwise pass for an address
+        let address = first.trim_start_matches('$');
+        if address.len() == 4 && address.chars().all(|c| c.is_ascii_hexdigit()) {
+            tokens.next();
+            let address = u16::from_str_radix(address, 16).map_err(|e| Error::Parse {
+                line: number + 1,
+                message: e.to_string(),
+            })?;
+            let mut offset = 0u16;
+            while let Some(token) = tokens.peek().filter(|t| is_hex_byte(t)) {
+                let byte = u8::from_str_radix(token, 16).map_err(|e| Error::Parse {
+                    line: number + 1,
+                    message: e.to_string(),
+                })?;
+                let target = address.checked_add(offset).ok_or_else(|| Error::Parse {
+                    line: number + 1,
+                    message: "bytes run past $FFFF".to_string(),
+                })?;
+                cpu.set_memory_at_address(target, byte);
+                offset += 1;
+                tokens.next();
+            }
+            for label in pending_labels.drain(..) {
+                symbols.insert(address, label);
+            }
+            if let Some(label) = tokens.next().and_then(|t| t.strip_suffix(':')) {
+                symbols.insert(address, label.to_string());
+            }
+        } else if let Some(label) = first.strip_suffix(':') {
+            pending_labels.push(label.to_string());
+        }
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LISTING: &str = "\
+; clears the accumulator then spins
+C000  A9 00     start:  LDA #$00
+C002  4C 02 C0  loop:   JMP loop
+";
+
+    #[test]
+    fn loads_bytes_and_labels() {
+        let mut cpu = CPU::new();
+        let symbols = load_listing(&mut cpu, LISTING).unwrap();
+        assert_eq!(symbols.get(&0xC000).map(String::as_str), Some("start"));
+        assert_eq!(symbols.get(&0xC002).map(String::as_str), Some("loop"));
+        assert_eq!(symbols.len(), 2);
+        assert_eq!((cpu.peek(0xC000), cpu.peek(0xC001)), (0xA9, 0x00));
+        assert_eq!(cpu.peek16(0xC003), 0xC002);
+    }
+
+    #[test]
+    fn hex_looking_label_on_its_own_line_is_a_label() {
+        let mut cpu = CPU::new();
+        let symbols = load_listing(&mut cpu, "cafe:\nC000  A9 00  LDA #$00").unwrap();
+        assert_eq!(symbols.get(&0xC000).map(String::as_str), Some("cafe"));
+    }
+}
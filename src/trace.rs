@@ -0,0 +1,182 @@
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+
+use crate::{cpu::Variant, disasm};
+
+// One executed instruction, taken just before it runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub opcode: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    pub cycle: u32,
+}
+
+impl TraceRecord {
+    // Bytes per record in a binary trace
+    pub const SIZE: usize = 12;
+
+    // Layout is pc (2), opcode, a, x, y, p, sp (1 each) then cycle (4),
+    // multi-byte fields little-endian like everything else here
+    pub fn to_bytes(self) -> [u8; TraceRecord::SIZE] {
+        let pc = self.pc.to_le_bytes();
+        let cycle = self.cycle.to_le_bytes();
+        [
+            pc[0], pc[1], self.opcode, self.a, self.x, self.y, self.p, self.sp,
+            cycle[0], cycle[1], cycle[2], cycle[3],
+        ]
+    }
+
+    pub fn from_bytes(bytes: &[u8; TraceRecord::SIZE]) -> Self {
+        Self {
+            pc: u16::from_le_bytes([bytes[0], bytes[1]]),
+            opcode: bytes[2],
+            a: bytes[3],
+            x: bytes[4],
+            y: bytes[5],
+            p: bytes[6],
+            sp: bytes[7],
+            cycle: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+        }
+    }
+}
+
+impl TraceRecord {
+    // The text line with the instruction's bytes and disassembly in place of
+    // just the opcode, laid out like nestest's logs so the two can be diffed,
+    // EG. "C000  A9 50     LDA #$50    ...    A:00 X:00 ..."
+    pub fn with_bytes(&self, bytes: &[u8]) -> String {
+        self.with_bytes_for(Variant::Nmos, bytes)
+    }
+
+    // Same as with_bytes but disassembled for the given chip
+    pub fn with_bytes_for(&self, variant: Variant, bytes: &[u8]) -> String {
+        let operands = bytes.get(1..).unwrap_or(&[]);
+        let text = disasm::format_instruction_for(variant, self.pc, self.opcode, operands);
+        // Illegal opcodes take the space before the mnemonic for their asterisk
+        let text = if text.starts_with('*') { text } else { format!(" {}", text) };
+        let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!("{:04X}  {:<8} {:<33}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            self.pc, bytes.join(" "), text, self.a, self.x, self.y, self.p, self.sp, self.cycle)
+    }
+}
+
+// One step of CPU::run_steps, unlike TraceRecord the registers are the ones
+// left behind by the instruction so the whole run can be compared against
+// an expected sequence
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceEntry {
+    // Where the instruction was
+    pub pc: u16,
+    pub opcode: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    pub cycles: u8,
+}
+
+// The opcode and operand bytes of the instruction about to be executed, as
+// they are before it runs. At most three since that's the longest instruction
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Fetched {
+    bytes: [u8; 3],
+    length: usize,
+}
+
+impl Fetched {
+    pub fn clear(&mut self) {
+        self.length = 0;
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        if self.length < self.bytes.len() {
+            self.bytes[self.length] = byte;
+            self.length += 1;
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.length]
+    }
+}
+
+impl Display for TraceRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04X}  {:02X}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            self.pc, self.opcode, self.a, self.x, self.y, self.p, self.sp, self.cycle)
+    }
+}
+
+// Writes fixed size records, a lot smaller than text for long runs
+pub struct BinaryTrace {
+    writer: Box<dyn Write>,
+}
+
+impl BinaryTrace {
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        Self { writer }
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        self.writer.write_all(&record.to_bytes())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub fn read_binary_trace<R: Read>(reader: &mut R) -> io::Result<Vec<TraceRecord>> {
+    let mut records = Vec::new();
+    let mut bytes = [0; TraceRecord::SIZE];
+    loop {
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => records.push(TraceRecord::from_bytes(&bytes)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(records)
+}
+
+// Turns a binary trace back into one line of text per record
+pub fn binary_trace_to_text<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut text = String::new();
+    for record in read_binary_trace(reader)? {
+        text.push_str(&record.to_string());
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_survive_the_trip_through_bytes() {
+        let record = TraceRecord { pc: 0xC5F5, opcode: 0xA9, a: 0x12, x: 0x34, y: 0x56, p: 0xA5, sp: 0xFB, cycle: 0x0102_0304 };
+        let bytes = record.to_bytes();
+        assert_eq!(bytes, [0xF5, 0xC5, 0xA9, 0x12, 0x34, 0x56, 0xA5, 0xFB, 0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(TraceRecord::from_bytes(&bytes), record);
+    }
+
+    #[test]
+    fn binary_trace_reads_back_as_text() {
+        let first = TraceRecord { pc: 0x0200, opcode: 0xA9, a: 0, x: 0, y: 0, p: 0x24, sp: 0xFD, cycle: 7 };
+        let second = TraceRecord { pc: 0x0202, opcode: 0xEA, a: 0x80, x: 1, y: 2, p: 0xA4, sp: 0xFD, cycle: 9 };
+        let mut bytes = first.to_bytes().to_vec();
+        bytes.extend_from_slice(&second.to_bytes());
+        // A cut off record at the end is dropped rather than misread
+        bytes.extend_from_slice(&[0x04, 0x02]);
+        let text = binary_trace_to_text(&mut io::Cursor::new(bytes)).unwrap();
+        assert_eq!(text, "0200  A9  A:00 X:00 Y:00 P:24 SP:FD CYC:7\n\
+                          0202  EA  A:80 X:01 Y:02 P:A4 SP:FD CYC:9\n");
+    }
+}
@@ -0,0 +1,106 @@
+use std::io::{self, BufRead, Write};
+
+use crate::{bus::Bus, cpu::CPU};
+
+// Text viewer for stepping through a program by hand, shows the registers,
+// flags, the next few instructions and a page of memory
+pub struct Viewer {
+    // First address shown in the memory pane
+    pub memory_start: u16,
+    // How many instructions to disassemble from PC
+    pub disassembly_lines: usize,
+}
+
+impl Default for Viewer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Viewer {
+    pub fn new() -> Self {
+        Self {
+            memory_start: 0x0000,
+            disassembly_lines: 8,
+        }
+    }
+
+    // Draws one frame of the current CPU state
+    pub fn render<B: Bus, W: Write>(&self, cpu: &CPU<B>, out: &mut W) -> io::Result<()> {
+        let registers = cpu.registers();
+        let sr = registers.sr;
+        writeln!(out, "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} CYC:{}",
+            registers.pc, registers.ac, registers.x, registers.y, registers.sp, cpu.cycles())?;
+        let flags = [
+            ('N', sr.negative), ('V', sr.overflow), ('-', sr.ignored), ('B', sr.sbreak),
+            ('D', sr.decimal), ('I', sr.interrupt), ('Z', sr.zero), ('C', sr.carry),
+        ];
+        let flags: String = flags.iter()
+            .map(|(name, set)| if *set { *name } else { '.' })
+            .collect();
+        writeln!(out, "Flags: {}", flags)?;
+        writeln!(out)?;
+
+        for (address, bytes, text) in cpu.disassemble(registers.pc).take(self.disassembly_lines) {
+            let marker = if address == registers.pc { '>' } else { ' ' };
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            writeln!(out, "{} {:04X}  {:<8}  {}", marker, address, hex.join(" "), text)?;
+        }
+        writeln!(out)?;
+
+        for row in 0..8u16 {
+            let address = self.memory_start.wrapping_add(row * 16);
+            let bytes: Vec<String> = (0..16)
+                .map(|i| format!("{:02X}", { cpu.inspect_memory_at_address(address.wrapping_add(i)) }))
+                .collect();
+            writeln!(out, "{:04X}: {}", address, bytes.join(" "))?;
+        }
+        Ok(())
+    }
+
+    // Enter steps, "m XXXX" moves the memory pane, "q" quits
+    pub fn run<B: Bus>(&mut self, cpu: &mut CPU<B>) -> io::Result<()> {
+        let stdin = io::stdin();
+        let mut stdout = io::stdout();
+        loop {
+            // Clear the screen and go back to the top left
+            write!(stdout, "\x1B[2J\x1B[H")?;
+            self.render(cpu, &mut stdout)?;
+            write!(stdout, "\n[Enter] step  [m XXXX] memory  [q] quit > ")?;
+            stdout.flush()?;
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = line.trim();
+            if line == "q" {
+                return Ok(());
+            } else if let Some(address) = line.strip_prefix("m ") {
+                if let Ok(address) = u16::from_str_radix(address.trim().trim_start_matches('$'), 16) {
+                    self.memory_start = address;
+                }
+            } else {
+                cpu.step();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_a_frame() {
+        let mut cpu = CPU::new();
+        cpu.load_program(0x0200, &[0xA9, 0x01]).unwrap();
+        cpu.set_pc(0x0200);
+        let mut out = Vec::new();
+        Viewer::new().render(&cpu, &mut out).unwrap();
+        let frame = String::from_utf8(out).unwrap();
+        assert!(frame.starts_with("PC:0200 A:00"));
+        assert!(frame.contains("> 0200  A9 01     LDA #$01"));
+        assert!(frame.contains("0000: EA EA"));
+    }
+}